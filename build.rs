@@ -0,0 +1,20 @@
+extern crate cc;
+
+use std::env;
+use std::path::PathBuf;
+
+fn main() {
+    let mut build = cc::Build::new();
+    build.cpp(true)
+        .flag("-std=c++11")
+        .file("src/native.cc");
+
+    // the same variable v8-sys reads to find V8's headers
+    if let Some(source) = env::var_os("V8_SOURCE") {
+        build.include(PathBuf::from(source).join("include"));
+    }
+    build.compile("serverless_native");
+
+    println!("cargo:rerun-if-changed=src/native.cc");
+    println!("cargo:rerun-if-env-changed=V8_SOURCE");
+}
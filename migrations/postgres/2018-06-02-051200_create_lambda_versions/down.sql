@@ -0,0 +1,2 @@
+DROP TABLE lambda_versions;
+ALTER TABLE lambdas DROP COLUMN version;
@@ -0,0 +1,14 @@
+ALTER TABLE lambdas ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+CREATE TABLE lambda_versions (
+  id SERIAL PRIMARY KEY,
+  lambda_id INTEGER NOT NULL REFERENCES lambdas(id) ON DELETE CASCADE,
+  version INTEGER NOT NULL,
+  code TEXT NOT NULL,
+  created_at BIGINT NOT NULL,
+  UNIQUE(lambda_id, version)
+);
+
+-- existing lambdas become version 1 of themselves
+INSERT INTO lambda_versions (lambda_id, version, code, created_at)
+  SELECT id, 1, code, extract(epoch from now())::bigint FROM lambdas;
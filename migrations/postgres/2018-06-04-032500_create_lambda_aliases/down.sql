@@ -0,0 +1 @@
+DROP TABLE lambda_aliases;
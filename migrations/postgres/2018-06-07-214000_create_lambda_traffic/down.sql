@@ -0,0 +1,2 @@
+DROP TABLE lambda_traffic;
+ALTER TABLE lambdas DROP COLUMN sticky;
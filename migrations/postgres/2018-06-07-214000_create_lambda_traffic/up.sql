@@ -0,0 +1,9 @@
+ALTER TABLE lambdas ADD COLUMN sticky TEXT;
+
+CREATE TABLE lambda_traffic (
+  id SERIAL PRIMARY KEY,
+  lambda_id INTEGER NOT NULL REFERENCES lambdas(id) ON DELETE CASCADE,
+  version INTEGER NOT NULL,
+  weight INTEGER NOT NULL CHECK (weight >= 0),
+  UNIQUE(lambda_id, version)
+);
@@ -0,0 +1,4 @@
+DELETE FROM lambdas WHERE methods <> '*';
+ALTER TABLE lambdas DROP CONSTRAINT lambdas_hostname_path_methods_key;
+ALTER TABLE lambdas ADD CONSTRAINT lambdas_hostname_path_key UNIQUE(hostname, path);
+ALTER TABLE lambdas DROP COLUMN methods;
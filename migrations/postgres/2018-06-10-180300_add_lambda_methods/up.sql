@@ -0,0 +1,3 @@
+ALTER TABLE lambdas ADD COLUMN methods TEXT NOT NULL DEFAULT '*';
+ALTER TABLE lambdas DROP CONSTRAINT lambdas_hostname_path_key;
+ALTER TABLE lambdas ADD CONSTRAINT lambdas_hostname_path_methods_key UNIQUE(hostname, path, methods);
@@ -0,0 +1,2 @@
+ALTER TABLE lambdas DROP COLUMN cpu_timeout_ms;
+ALTER TABLE lambdas DROP COLUMN timeout_ms;
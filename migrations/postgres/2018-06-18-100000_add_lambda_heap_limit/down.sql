@@ -0,0 +1 @@
+ALTER TABLE lambdas DROP COLUMN heap_limit_mb;
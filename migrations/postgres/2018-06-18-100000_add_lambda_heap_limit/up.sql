@@ -0,0 +1 @@
+ALTER TABLE lambdas ADD COLUMN heap_limit_mb INTEGER;
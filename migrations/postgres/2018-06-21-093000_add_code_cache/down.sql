@@ -0,0 +1,4 @@
+ALTER TABLE lambda_versions DROP COLUMN code_cache_tag;
+ALTER TABLE lambda_versions DROP COLUMN code_cache;
+ALTER TABLE lambdas DROP COLUMN code_cache_tag;
+ALTER TABLE lambdas DROP COLUMN code_cache;
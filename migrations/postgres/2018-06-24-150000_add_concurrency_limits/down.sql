@@ -0,0 +1,2 @@
+DROP TABLE host_limits;
+ALTER TABLE lambdas DROP COLUMN max_concurrency;
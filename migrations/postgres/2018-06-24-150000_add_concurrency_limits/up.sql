@@ -0,0 +1,5 @@
+ALTER TABLE lambdas ADD COLUMN max_concurrency INTEGER;
+CREATE TABLE host_limits (
+  hostname TEXT PRIMARY KEY,
+  max_concurrency INTEGER
+);
@@ -0,0 +1,4 @@
+ALTER TABLE host_limits DROP COLUMN rate_limit_key;
+ALTER TABLE host_limits DROP COLUMN rate_limit;
+ALTER TABLE lambdas DROP COLUMN rate_limit_key;
+ALTER TABLE lambdas DROP COLUMN rate_limit;
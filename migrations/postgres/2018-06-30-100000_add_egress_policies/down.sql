@@ -0,0 +1,2 @@
+DROP TABLE host_egress;
+ALTER TABLE lambdas DROP COLUMN egress_policy;
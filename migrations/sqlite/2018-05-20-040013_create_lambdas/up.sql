@@ -0,0 +1,8 @@
+CREATE TABLE lambdas (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  path TEXT NOT NULL,
+  hostname TEXT NOT NULL,
+  code TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX lambdas_hostname_path_key ON lambdas (hostname, path);
@@ -0,0 +1,16 @@
+DROP TABLE lambda_versions;
+
+-- SQLite cannot drop columns, the table is rebuilt without them. Run with
+-- foreign keys off (the sqlite3 and diesel CLI default), dropping lambdas
+-- would otherwise cascade to the tables referencing it.
+CREATE TABLE lambdas_new (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  path TEXT NOT NULL,
+  hostname TEXT NOT NULL,
+  code TEXT NOT NULL
+);
+INSERT INTO lambdas_new (id, path, hostname, code)
+  SELECT id, path, hostname, code FROM lambdas;
+DROP TABLE lambdas;
+ALTER TABLE lambdas_new RENAME TO lambdas;
+CREATE UNIQUE INDEX lambdas_hostname_path_key ON lambdas (hostname, path);
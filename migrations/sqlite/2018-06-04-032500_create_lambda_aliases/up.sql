@@ -0,0 +1,7 @@
+CREATE TABLE lambda_aliases (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  lambda_id INTEGER NOT NULL REFERENCES lambdas(id) ON DELETE CASCADE,
+  name TEXT NOT NULL,
+  version INTEGER NOT NULL,
+  UNIQUE(lambda_id, name)
+);
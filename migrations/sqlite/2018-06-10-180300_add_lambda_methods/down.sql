@@ -0,0 +1,19 @@
+DELETE FROM lambdas WHERE methods <> '*';
+DROP INDEX lambdas_hostname_path_methods_key;
+
+-- SQLite cannot drop columns, the table is rebuilt without them. Run with
+-- foreign keys off (the sqlite3 and diesel CLI default), dropping lambdas
+-- would otherwise cascade to the tables referencing it.
+CREATE TABLE lambdas_new (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  path TEXT NOT NULL,
+  hostname TEXT NOT NULL,
+  code TEXT NOT NULL,
+  version INTEGER NOT NULL DEFAULT 1,
+  sticky TEXT
+);
+INSERT INTO lambdas_new (id, path, hostname, code, version, sticky)
+  SELECT id, path, hostname, code, version, sticky FROM lambdas;
+DROP TABLE lambdas;
+ALTER TABLE lambdas_new RENAME TO lambdas;
+CREATE UNIQUE INDEX lambdas_hostname_path_key ON lambdas (hostname, path);
@@ -0,0 +1,3 @@
+ALTER TABLE lambdas ADD COLUMN methods TEXT NOT NULL DEFAULT '*';
+DROP INDEX lambdas_hostname_path_key;
+CREATE UNIQUE INDEX lambdas_hostname_path_methods_key ON lambdas (hostname, path, methods);
@@ -0,0 +1,7 @@
+CREATE TABLE api_keys (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL,
+  key_hash TEXT NOT NULL UNIQUE,
+  hostnames TEXT NOT NULL,
+  created_at BIGINT NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE lambdas ADD COLUMN timeout_ms INTEGER;
+ALTER TABLE lambdas ADD COLUMN cpu_timeout_ms INTEGER;
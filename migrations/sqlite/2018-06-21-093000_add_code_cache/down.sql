@@ -0,0 +1,33 @@
+-- SQLite cannot drop columns, both tables are rebuilt without them.
+CREATE TABLE lambda_versions_new (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  lambda_id INTEGER NOT NULL REFERENCES lambdas(id) ON DELETE CASCADE,
+  version INTEGER NOT NULL,
+  code TEXT NOT NULL,
+  created_at BIGINT NOT NULL,
+  UNIQUE(lambda_id, version)
+);
+INSERT INTO lambda_versions_new (id, lambda_id, version, code, created_at)
+  SELECT id, lambda_id, version, code, created_at FROM lambda_versions;
+DROP TABLE lambda_versions;
+ALTER TABLE lambda_versions_new RENAME TO lambda_versions;
+
+-- Run with foreign keys off (the sqlite3 and diesel CLI default), dropping
+-- lambdas would otherwise cascade to the tables referencing it.
+CREATE TABLE lambdas_new (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  path TEXT NOT NULL,
+  hostname TEXT NOT NULL,
+  code TEXT NOT NULL,
+  version INTEGER NOT NULL DEFAULT 1,
+  sticky TEXT,
+  methods TEXT NOT NULL DEFAULT '*',
+  timeout_ms INTEGER,
+  cpu_timeout_ms INTEGER,
+  heap_limit_mb INTEGER
+);
+INSERT INTO lambdas_new (id, path, hostname, code, version, sticky, methods, timeout_ms, cpu_timeout_ms, heap_limit_mb)
+  SELECT id, path, hostname, code, version, sticky, methods, timeout_ms, cpu_timeout_ms, heap_limit_mb FROM lambdas;
+DROP TABLE lambdas;
+ALTER TABLE lambdas_new RENAME TO lambdas;
+CREATE UNIQUE INDEX lambdas_hostname_path_methods_key ON lambdas (hostname, path, methods);
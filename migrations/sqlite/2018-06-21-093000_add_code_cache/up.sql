@@ -0,0 +1,4 @@
+ALTER TABLE lambdas ADD COLUMN code_cache BLOB;
+ALTER TABLE lambdas ADD COLUMN code_cache_tag TEXT;
+ALTER TABLE lambda_versions ADD COLUMN code_cache BLOB;
+ALTER TABLE lambda_versions ADD COLUMN code_cache_tag TEXT;
@@ -0,0 +1,32 @@
+CREATE TABLE host_limits_new (
+  hostname TEXT PRIMARY KEY,
+  max_concurrency INTEGER
+);
+INSERT INTO host_limits_new (hostname, max_concurrency)
+  SELECT hostname, max_concurrency FROM host_limits;
+DROP TABLE host_limits;
+ALTER TABLE host_limits_new RENAME TO host_limits;
+
+-- SQLite cannot drop columns, the table is rebuilt without them. Run with
+-- foreign keys off (the sqlite3 and diesel CLI default), dropping lambdas
+-- would otherwise cascade to the tables referencing it.
+CREATE TABLE lambdas_new (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  path TEXT NOT NULL,
+  hostname TEXT NOT NULL,
+  code TEXT NOT NULL,
+  version INTEGER NOT NULL DEFAULT 1,
+  sticky TEXT,
+  methods TEXT NOT NULL DEFAULT '*',
+  timeout_ms INTEGER,
+  cpu_timeout_ms INTEGER,
+  heap_limit_mb INTEGER,
+  code_cache BLOB,
+  code_cache_tag TEXT,
+  max_concurrency INTEGER
+);
+INSERT INTO lambdas_new (id, path, hostname, code, version, sticky, methods, timeout_ms, cpu_timeout_ms, heap_limit_mb, code_cache, code_cache_tag, max_concurrency)
+  SELECT id, path, hostname, code, version, sticky, methods, timeout_ms, cpu_timeout_ms, heap_limit_mb, code_cache, code_cache_tag, max_concurrency FROM lambdas;
+DROP TABLE lambdas;
+ALTER TABLE lambdas_new RENAME TO lambdas;
+CREATE UNIQUE INDEX lambdas_hostname_path_methods_key ON lambdas (hostname, path, methods);
@@ -0,0 +1,4 @@
+ALTER TABLE lambdas ADD COLUMN rate_limit INTEGER;
+ALTER TABLE lambdas ADD COLUMN rate_limit_key TEXT;
+ALTER TABLE host_limits ADD COLUMN rate_limit INTEGER;
+ALTER TABLE host_limits ADD COLUMN rate_limit_key TEXT;
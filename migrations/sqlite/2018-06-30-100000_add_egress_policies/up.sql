@@ -0,0 +1,5 @@
+ALTER TABLE lambdas ADD COLUMN egress_policy TEXT;
+CREATE TABLE host_egress (
+  hostname TEXT PRIMARY KEY,
+  policy TEXT NOT NULL
+);
@@ -0,0 +1,250 @@
+extern crate rand;
+extern crate sha2;
+
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use actix_web::middleware::{Middleware, Started};
+use futures::Future;
+use self::rand::Rng;
+use self::sha2::{Digest, Sha256};
+
+use db::{AppState, FindApiKey};
+use error;
+use models;
+use request;
+
+/// Hostname scope granting access to every hostname and to key management.
+pub const ANY_HOST: &str = "*";
+
+#[derive(Deserialize)]
+pub struct KeyParams {
+    pub name: String,
+    pub hostnames: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct KeyPath {
+    pub id: i32,
+}
+
+/// Returned once when a key is created, only its hash is stored.
+#[derive(Serialize)]
+pub struct IssuedKey {
+    pub id: i32,
+    pub name: String,
+    pub hostnames: String,
+    pub key: String,
+}
+
+/// The caller of an admin request, stored in the request extensions by
+/// `Authentication`.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    hostnames: Vec<String>,
+}
+
+impl Identity {
+    fn admin() -> Identity {
+        Identity { name: "admin".to_string(), hostnames: vec![ANY_HOST.to_string()] }
+    }
+
+    fn from_key(key: &models::ApiKey) -> Identity {
+        Identity {
+            name: key.name.clone(),
+            hostnames: key.hostnames.split(',').map(|h| h.to_string()).collect(),
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.hostnames.iter().any(|h| h == ANY_HOST)
+    }
+
+    pub fn allows(&self, hostname: &str) -> bool {
+        self.is_admin() || self.hostnames.iter().any(|h| h.eq_ignore_ascii_case(hostname))
+    }
+}
+
+pub fn generate_key() -> String {
+    rand::thread_rng().gen_ascii_chars().take(40).collect()
+}
+
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Lower cases, sorts and de-duplicates a key's hostname scope.
+pub fn normalize_hostnames(hostnames: &[String]) -> Result<String, String> {
+    let mut ret: Vec<String> = hostnames.iter()
+        .map(|h| h.trim().to_lowercase())
+        .filter(|h| !h.is_empty())
+        .collect();
+    if ret.is_empty() {
+        return Err("At least one hostname is required".to_string());
+    }
+    if ret.iter().any(|h| h == ANY_HOST) {
+        return Ok(ANY_HOST.to_string());
+    }
+    ret.sort();
+    ret.dedup();
+    Ok(ret.join(","))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// `Authorization: Bearer <key>` or `X-Api-Key: <key>`
+fn token(req: &HttpRequest<AppState>) -> Option<String> {
+    let headers = req.headers();
+    if let Some(value) = headers.get("authorization").and_then(|v| v.to_str().ok()) {
+        if value.len() > 7 && value[..7].eq_ignore_ascii_case("bearer ") {
+            return Some(value[7..].trim().to_string());
+        }
+    }
+    headers.get("x-api-key")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+}
+
+/// The hostname an admin request operates on, read from the `Host` header
+/// exactly as the handlers read it. Listing lambdas without a `hostname`
+/// filter has no single target, the handler filters the result.
+fn target_host<S>(req: &HttpRequest<S>) -> Result<Option<String>, error::Error> {
+    if req.path() == "/v1/lambda" {
+        return Ok(req.query().get("hostname").map(|h| h.to_lowercase()));
+    }
+    request::hostname(req).map(Some)
+}
+
+/// Why `identity` may not make `req`, `None` when it may.
+fn refusal<S>(identity: &Identity, req: &HttpRequest<S>) -> Option<HttpResponse> {
+    if req.path().starts_with("/v1/keys") || req.path() == "/v1/metrics" {
+        return if identity.is_admin() { None } else { Some(forbidden()) };
+    }
+    match target_host(req) {
+        Ok(Some(ref host)) if !identity.allows(host) => Some(forbidden()),
+        Ok(_) => None,
+        Err(e) => Some(e.error_response()),
+    }
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .header("WWW-Authenticate", "Bearer")
+        .body("Unauthorized")
+}
+
+fn forbidden() -> HttpResponse {
+    HttpResponse::Forbidden().body("Forbidden")
+}
+
+/// Requires an API key on every `/v1/` request. Keys are only valid for the
+/// hostnames they are scoped to, key management and metrics need a `*`
+/// scoped key.
+pub struct Authentication;
+
+impl Middleware<AppState> for Authentication {
+    fn start(&self, req: &mut HttpRequest<AppState>) -> Result<Started, Error> {
+        if !req.path().starts_with("/v1/") {
+            return Ok(Started::Done);
+        }
+
+        let token =
+            match token(req) {
+                Some(t) => t,
+                None => return Ok(Started::Response(unauthorized())),
+            };
+
+        let admin =
+            match req.state().admin_key {
+                Some(ref key) => constant_time_eq(key.as_bytes(), token.as_bytes()),
+                None => false,
+            };
+        if admin {
+            req.extensions_mut().insert(Identity::admin());
+            return Ok(Started::Done);
+        }
+
+        let mut req = req.clone();
+        let fut = req.state()
+            .db
+            .send(FindApiKey { key_hash: hash_key(&token) })
+            .from_err()
+            .and_then(move |res| match res {
+                Ok(Some(key)) => {
+                    let identity = Identity::from_key(&key);
+                    let refused = refusal(&identity, &req);
+                    if refused.is_none() {
+                        req.extensions_mut().insert(identity);
+                    }
+                    Ok(refused)
+                },
+                Ok(None) => Ok(Some(unauthorized())),
+                Err(e) => Ok(Some(e.error_response())),
+            });
+
+        Ok(Started::Future(Box::new(fut)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    fn scoped(hostname: &str) -> Identity {
+        Identity { name: "deploy".to_string(), hostnames: vec![hostname.to_string()] }
+    }
+
+    fn status(identity: &Identity, req: TestRequest<()>) -> Option<StatusCode> {
+        refusal(identity, &req.finish()).map(|r| r.status())
+    }
+
+    #[test]
+    fn allows_the_scoped_host() {
+        let req = TestRequest::with_uri("/v1/lambda/hello").header("host", "A.com:8088");
+        assert_eq!(status(&scoped("a.com"), req), None);
+    }
+
+    #[test]
+    fn ignores_forwarded_hosts() {
+        let req = TestRequest::with_uri("/v1/lambda/hello")
+            .header("host", "victim.com")
+            .header("x-forwarded-host", "a.com");
+        assert_eq!(status(&scoped("a.com"), req), Some(StatusCode::FORBIDDEN));
+
+        let req = TestRequest::with_uri("/v1/lambda/hello")
+            .header("host", "victim.com")
+            .header("forwarded", "host=a.com");
+        assert_eq!(status(&scoped("a.com"), req), Some(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn requires_a_host() {
+        let req = TestRequest::with_uri("/v1/lambda/hello").header("x-forwarded-host", "a.com");
+        assert_eq!(status(&scoped("a.com"), req), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn key_management_needs_an_admin_key() {
+        let req = TestRequest::with_uri("/v1/keys").header("host", "a.com");
+        assert_eq!(status(&scoped("a.com"), req), Some(StatusCode::FORBIDDEN));
+        let req = TestRequest::with_uri("/v1/keys").header("host", "a.com");
+        assert_eq!(status(&Identity::admin(), req), None);
+    }
+
+    #[test]
+    fn lists_by_the_hostname_filter() {
+        let req = TestRequest::with_uri("/v1/lambda?hostname=b.com").header("host", "a.com");
+        assert_eq!(status(&scoped("a.com"), req), Some(StatusCode::FORBIDDEN));
+        let req = TestRequest::with_uri("/v1/lambda").header("host", "b.com");
+        assert_eq!(status(&scoped("a.com"), req), None);
+    }
+}
@@ -0,0 +1,266 @@
+extern crate fallible_iterator;
+extern crate postgres;
+
+use std::collections::HashMap;
+use std::error;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use diesel;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use diesel::sqlite::SqliteConnection;
+use self::fallible_iterator::FallibleIterator;
+
+use models;
+use router;
+use storage;
+
+/// `NOTIFY` channel carrying the hostname whose lambdas changed.
+pub const CHANGES_CHANNEL: &str = "lambda_changes";
+
+/// How often SQLite is checked for writes by other processes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Pause before reconnecting a lost `LISTEN` connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Everything `GetLambda` needs to route the requests for one hostname.
+pub struct Host {
+    pub routes: router::Routes,
+    /// Traffic routes by lambda id.
+    pub traffic: HashMap<i32, Vec<models::LambdaTraffic>>,
+    /// Aliases by lambda id.
+    pub aliases: HashMap<i32, Vec<models::LambdaAlias>>,
+    pub limits: models::HostLimits,
+    /// The hostname's egress policy as JSON.
+    pub egress: Option<String>,
+}
+
+/// How often a hostname was dropped, read before loading it so that data
+/// loaded while it changed is not cached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Generation {
+    /// Bumped by `clear`.
+    all: u64,
+    /// Bumped by `invalidate`.
+    host: u64,
+}
+
+#[derive(Default)]
+struct Hosts {
+    loaded: HashMap<String, (Instant, Arc<Host>)>,
+    generations: HashMap<String, u64>,
+    cleared: u64,
+}
+
+impl Hosts {
+    fn generation(&self, hostname: &str) -> Generation {
+        Generation { all: self.cleared, host: self.generations.get(hostname).cloned().unwrap_or(0) }
+    }
+}
+
+/// Routing data and versions kept between requests. Entries expire after
+/// `ttl`, a hostname is dropped as soon as one of its lambdas changes. A `ttl`
+/// of zero disables the cache.
+pub struct LambdaCache {
+    ttl: Duration,
+    hosts: Mutex<Hosts>,
+    /// Versions by (lambda id, version). Deployed versions never change.
+    versions: Mutex<HashMap<(i32, i32), (Instant, models::LambdaVersion)>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    // the maps stay consistent even if a holder panicked
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl LambdaCache {
+    pub fn new(ttl: Duration) -> LambdaCache {
+        LambdaCache {
+            ttl: ttl,
+            hosts: Mutex::new(Hosts::default()),
+            versions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn fresh(&self, loaded: Instant) -> bool {
+        loaded.elapsed() < self.ttl
+    }
+
+    pub fn host(&self, hostname: &str) -> Option<Arc<Host>> {
+        match lock(&self.hosts).loaded.get(hostname) {
+            Some(&(loaded, ref host)) if self.fresh(loaded) => Some(host.clone()),
+            _ => None,
+        }
+    }
+
+    /// To be read before loading `hostname` and passed to `put_host`.
+    pub fn generation(&self, hostname: &str) -> Generation {
+        lock(&self.hosts).generation(hostname)
+    }
+
+    /// Caches `host` unless `hostname` was dropped since `generation` was
+    /// read, what was loaded may predate the change then.
+    pub fn put_host(&self, hostname: &str, generation: Generation, host: Host) -> Arc<Host> {
+        let host = Arc::new(host);
+        if self.ttl > Duration::from_secs(0) {
+            let mut hosts = lock(&self.hosts);
+            if hosts.generation(hostname) == generation {
+                let ttl = self.ttl;
+                hosts.loaded.retain(|_, &mut (loaded, _)| loaded.elapsed() < ttl);
+                hosts.loaded.insert(hostname.to_string(), (Instant::now(), host.clone()));
+            }
+        }
+        host
+    }
+
+    pub fn version(&self, lambda_id: i32, version: i32) -> Option<models::LambdaVersion> {
+        match lock(&self.versions).get(&(lambda_id, version)) {
+            Some(&(loaded, ref v)) if self.fresh(loaded) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn put_version(&self, version: &models::LambdaVersion) {
+        if self.ttl > Duration::from_secs(0) {
+            let mut versions = lock(&self.versions);
+            versions.retain(|_, &mut (loaded, _)| loaded.elapsed() < self.ttl);
+            versions.insert((version.lambda_id, version.version), (Instant::now(), version.clone()));
+        }
+    }
+
+    /// Drops a hostname after one of its lambdas changed.
+    pub fn invalidate(&self, hostname: &str) {
+        let mut hosts = lock(&self.hosts);
+        hosts.loaded.remove(hostname);
+        *hosts.generations.entry(hostname.to_string()).or_insert(0) += 1;
+    }
+
+    /// Drops every hostname, for when changes may have been missed.
+    pub fn clear(&self) {
+        let mut hosts = lock(&self.hosts);
+        hosts.loaded.clear();
+        hosts.generations.clear();
+        hosts.cleared += 1;
+    }
+}
+
+/// Keeps `cache` in step with writes made by other server instances. Postgres
+/// pushes them with `LISTEN`, SQLite is polled for any write.
+pub fn watch(database_url: &str, cache: Arc<LambdaCache>) -> Result<(), String> {
+    let database_url = database_url.to_string();
+    let spawned =
+        if database_url.starts_with("sqlite:") {
+            thread::Builder::new()
+                .name("cache-poll".to_string())
+                .spawn(move || poll(storage::sqlite_path(&database_url), &cache))
+        } else {
+            thread::Builder::new()
+                .name("cache-listen".to_string())
+                .spawn(move || listen(&database_url, &cache))
+        };
+    spawned
+        .map(|_| ())
+        .map_err(|e| format!("Unable to watch for lambda changes: {}", e))
+}
+
+fn listen(database_url: &str, cache: &LambdaCache) {
+    loop {
+        if let Err(e) = listen_once(database_url, cache) {
+            warn!("Lost the {} listener: {}", CHANGES_CHANNEL, e);
+        }
+        // notifications sent while disconnected are gone
+        cache.clear();
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+fn listen_once(database_url: &str, cache: &LambdaCache) -> Result<(), Box<error::Error>> {
+    let conn = postgres::Connection::connect(database_url, postgres::TlsMode::None)?;
+    conn.execute(&format!("LISTEN {}", CHANGES_CHANNEL), &[])?;
+    cache.clear();
+
+    let notifications = conn.notifications();
+    let mut changes = notifications.blocking_iter();
+    while let Some(change) = changes.next()? {
+        cache.invalidate(&change.payload);
+    }
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct DataVersion {
+    #[sql_type = "BigInt"]
+    data_version: i64,
+}
+
+/// `PRAGMA data_version` changes whenever another connection commits, which
+/// does not tell which hostname changed, so the whole cache is dropped.
+fn poll(path: &str, cache: &LambdaCache) {
+    let mut seen: Option<i64> = None;
+    let mut conn: Option<SqliteConnection> = None;
+    loop {
+        if conn.is_none() {
+            conn = SqliteConnection::establish(path)
+                .map_err(|e| warn!("Unable to poll {} for lambda changes: {}", path, e))
+                .ok();
+        }
+
+        if let Some(ref c) = conn {
+            match diesel::sql_query("PRAGMA data_version").load::<DataVersion>(c) {
+                Ok(rows) => {
+                    let current = rows.first().map(|r| r.data_version);
+                    if seen.is_some() && current != seen {
+                        cache.clear();
+                    }
+                    seen = current;
+                },
+                Err(e) => warn!("Unable to poll {} for lambda changes: {}", path, e),
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(hostname: &str) -> Host {
+        Host {
+            routes: router::Routes::new(Vec::new()),
+            traffic: HashMap::new(),
+            aliases: HashMap::new(),
+            limits: models::HostLimits { hostname: hostname.to_string(), ..Default::default() },
+            egress: None,
+        }
+    }
+
+    #[test]
+    fn caches_a_loaded_host() {
+        let cache = LambdaCache::new(Duration::from_secs(60));
+        let generation = cache.generation("example.com");
+        cache.put_host("example.com", generation, host("example.com"));
+        assert!(cache.host("example.com").is_some());
+    }
+
+    #[test]
+    fn skips_a_host_invalidated_while_loading() {
+        let cache = LambdaCache::new(Duration::from_secs(60));
+        let generation = cache.generation("example.com");
+        cache.invalidate("example.com");
+        cache.put_host("example.com", generation, host("example.com"));
+        assert!(cache.host("example.com").is_none());
+    }
+
+    #[test]
+    fn skips_a_host_cleared_while_loading() {
+        let cache = LambdaCache::new(Duration::from_secs(60));
+        let generation = cache.generation("example.com");
+        cache.clear();
+        cache.put_host("example.com", generation, host("example.com"));
+        assert!(cache.host("example.com").is_none());
+    }
+}
@@ -0,0 +1,179 @@
+extern crate tokio_core;
+
+use actix::Arbiter;
+use futures::{future, Future};
+use futures::future::Either;
+use futures::sync::oneshot;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use self::tokio_core::reactor::Timeout;
+
+use error::Error;
+use metrics::Metrics;
+use models::{HostLimits, Lambda};
+
+/// Executions running under one key and the requests waiting for them.
+#[derive(Default)]
+struct Gate {
+    running: usize,
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+enum Admission {
+    Run,
+    Wait(oneshot::Receiver<Permit>),
+    Full,
+}
+
+/// Caps concurrent executions per key, e.g. per lambda or per hostname.
+/// Requests over the cap wait in a queue of `queue_size` for at most
+/// `max_wait`, after that or with a full queue they are throttled.
+pub struct Limiter {
+    gates: Mutex<HashMap<String, Gate>>,
+    queue_size: usize,
+    max_wait: Duration,
+    metrics: Arc<Metrics>,
+}
+
+/// The right to run one execution, handed to the next waiter when dropped.
+pub struct Permit(Option<(Arc<Limiter>, String)>);
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Limiter {
+    pub fn new(queue_size: usize, max_wait: Duration, metrics: Arc<Metrics>) -> Limiter {
+        Limiter {
+            gates: Mutex::new(HashMap::new()),
+            queue_size: queue_size,
+            max_wait: max_wait,
+            metrics: metrics,
+        }
+    }
+
+    fn throttled(&self) -> Error {
+        self.metrics.throttled.fetch_add(1, Ordering::Relaxed);
+        // whole seconds, rounded up
+        let wait = self.max_wait.as_secs() + if self.max_wait.subsec_nanos() > 0 { 1 } else { 0 };
+        Error::Throttled {
+            message: "Too many concurrent executions".to_string(),
+            retry_after: wait.max(1),
+        }
+    }
+
+    /// Waits for one of `max` execution slots of `key`, right away when there
+    /// is no limit.
+    pub fn acquire(this: &Arc<Limiter>, key: String, max: Option<usize>) -> Box<Future<Item=Option<Permit>, Error=Error>> {
+        let max =
+            match max {
+                Some(m) => m,
+                None => return Box::new(future::ok(None)),
+            };
+
+        let admission = {
+            let mut gates = lock(&this.gates);
+            let gate = gates.entry(key.clone()).or_insert_with(Gate::default);
+            // waits that gave up do not count against the queue
+            gate.waiting.retain(|tx| !tx.is_canceled());
+            if gate.running < max {
+                gate.running += 1;
+                Admission::Run
+            } else if gate.waiting.len() < this.queue_size {
+                let (tx, rx) = oneshot::channel();
+                gate.waiting.push_back(tx);
+                Admission::Wait(rx)
+            } else {
+                Admission::Full
+            }
+        };
+
+        let rx =
+            match admission {
+                Admission::Run => return Box::new(future::ok(Some(Permit(Some((this.clone(), key)))))),
+                Admission::Wait(rx) => rx,
+                Admission::Full => return Box::new(future::err(this.throttled())),
+            };
+        this.metrics.queued.fetch_add(1, Ordering::Relaxed);
+
+        let timeout =
+            match Timeout::new(this.max_wait, Arbiter::handle()) {
+                Ok(t) => t,
+                Err(e) => return Box::new(future::err(Error::Internal(format!("Unable to start timer: {}", e)))),
+            };
+        let limiter = this.clone();
+        Box::new(rx.select2(timeout).then(move |res| match res {
+            Ok(Either::A((permit, _))) => Ok(Some(permit)),
+            // a permit sent after the wait gave up is dropped with the
+            // receiver and passed on to the next waiter
+            _ => Err(limiter.throttled()),
+        }))
+    }
+
+    /// Waits for an execution slot of the lambda and then one of the
+    /// hostname. Every request takes them in this order, so one waiting for
+    /// a busy lambda does not hold a slot the hostname's other lambdas could
+    /// run in.
+    pub fn acquire_route(this: &Arc<Limiter>, host: &HostLimits, lambda: &Lambda) -> Box<Future<Item=(Option<Permit>, Option<Permit>), Error=Error>> {
+        let limiter = this.clone();
+        let host_key = format!("host:{}", host.hostname);
+        let host_max = host.max_concurrency.map(|m| m as usize);
+        Box::new(Limiter::acquire(this, format!("lambda:{}", lambda.id), lambda.max_concurrency.map(|m| m as usize))
+            .and_then(move |lambda| {
+                Limiter::acquire(&limiter, host_key, host_max).map(|host| (host, lambda))
+            }))
+    }
+
+    fn release(this: &Arc<Limiter>, key: &str) {
+        loop {
+            let next = {
+                let mut gates = lock(&this.gates);
+                let idle =
+                    match gates.get_mut(key) {
+                        Some(gate) => match pop_waiting(gate) {
+                            Some(tx) => Some(tx),
+                            None => {
+                                gate.running -= 1;
+                                None
+                            },
+                        },
+                        None => return,
+                    };
+                if idle.is_none() && gates.get(key).map(|g| g.running == 0).unwrap_or(false) {
+                    gates.remove(key);
+                }
+                idle
+            };
+
+            match next {
+                // the slot moves to the waiter, `running` stays the same
+                Some(tx) => match tx.send(Permit(Some((this.clone(), key.to_string())))) {
+                    Ok(()) => return,
+                    // the waiter gave up, try the next one
+                    Err(mut permit) => permit.0 = None,
+                },
+                None => return,
+            }
+        }
+    }
+}
+
+/// The longest waiting request that has not given up yet.
+fn pop_waiting(gate: &mut Gate) -> Option<oneshot::Sender<Permit>> {
+    while let Some(tx) = gate.waiting.pop_front() {
+        if !tx.is_canceled() {
+            return Some(tx);
+        }
+    }
+    None
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some((limiter, key)) = self.0.take() {
+            Limiter::release(&limiter, &key);
+        }
+    }
+}
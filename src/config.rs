@@ -0,0 +1,113 @@
+use std::env;
+use std::time::Duration;
+
+use outbound::HttpConfig;
+use runtime::Limits;
+
+const USAGE: &str = "usage: serverless [--no-migrate] [migrate]";
+
+pub enum Command {
+    /// Run the HTTP server (the default).
+    Serve,
+    /// Apply pending migrations and exit.
+    Migrate,
+}
+
+pub struct Config {
+    pub command: Command,
+    pub database_url: String,
+    pub listen_addr: String,
+    /// Apply pending migrations before serving, disabled with `--no-migrate`.
+    pub auto_migrate: bool,
+    /// Bootstrap key for the admin API (`ADMIN_API_KEY`).
+    pub admin_key: Option<String>,
+    /// Default execution limits, `LAMBDA_TIMEOUT_MS`, `LAMBDA_CPU_TIMEOUT_MS`
+    /// and `LAMBDA_HEAP_LIMIT_MB`.
+    pub limits: Limits,
+    /// Threads running JavaScript (`JS_WORKERS`).
+    pub js_workers: usize,
+    /// Invocations allowed to wait for a free JavaScript thread (`JS_QUEUE_SIZE`).
+    pub js_queue: usize,
+    /// Warm isolates kept by each JavaScript thread (`JS_WARM_ISOLATES`).
+    pub warm_isolates: usize,
+    /// How long routing data is cached (`LAMBDA_CACHE_TTL_SECS`), zero disables
+    /// the cache.
+    pub cache_ttl: Duration,
+    /// Requests allowed to wait for a lambda or hostname at its concurrency
+    /// limit (`LAMBDA_QUEUE_SIZE`).
+    pub queue_size: usize,
+    /// How long they wait before they are throttled (`LAMBDA_QUEUE_WAIT_MS`).
+    pub queue_wait: Duration,
+    /// Outbound request defaults, `HTTP_TIMEOUT_MS`, `HTTP_CONNECT_TIMEOUT_MS`,
+    /// `HTTP_IDLE_TIMEOUT_MS`, `HTTP_USER_AGENT` and `HTTP_PROXY`.
+    pub http: HttpConfig,
+}
+
+fn var_or(name: &str, default: &str) -> String {
+    match env::var(name) {
+        Ok(v) => v,
+        Err(_) => default.to_string(),
+    }
+}
+
+fn positive(name: &str, default: u64, unit: &str) -> Result<u64, String> {
+    match env::var(name) {
+        Ok(v) => v.parse::<u64>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| format!("{} must be a positive number of {}", name, unit)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn millis(name: &str, default: u64) -> Result<Duration, String> {
+    positive(name, default, "milliseconds").map(Duration::from_millis)
+}
+
+impl Config {
+    /// Reads the configuration from the environment and command line.
+    pub fn from_env() -> Result<Config, String> {
+        let mut command = Command::Serve;
+        let mut auto_migrate = true;
+        for arg in env::args().skip(1) {
+            match arg.as_str() {
+                "migrate" => command = Command::Migrate,
+                "--no-migrate" => auto_migrate = false,
+                _ => return Err(format!("unknown argument `{}`\n{}", arg, USAGE)),
+            }
+        }
+
+        Ok(Config {
+            command: command,
+            database_url: var_or("DATABASE_URL", "postgres://postgres:@localhost/serverless"),
+            listen_addr: var_or("LISTEN_ADDR", "127.0.0.1:8088"),
+            auto_migrate: auto_migrate,
+            admin_key: env::var("ADMIN_API_KEY").ok().filter(|k| !k.is_empty()),
+            limits: Limits {
+                timeout: millis("LAMBDA_TIMEOUT_MS", 30000)?,
+                cpu_time: millis("LAMBDA_CPU_TIMEOUT_MS", 10000)?,
+                heap_mb: positive("LAMBDA_HEAP_LIMIT_MB", 128, "megabytes")? as usize,
+            },
+            js_workers: positive("JS_WORKERS", 4, "threads")? as usize,
+            js_queue: var_or("JS_QUEUE_SIZE", "64").parse::<usize>()
+                .map_err(|_| "JS_QUEUE_SIZE must be a number of invocations".to_string())?,
+            warm_isolates: var_or("JS_WARM_ISOLATES", "16").parse::<usize>()
+                .map_err(|_| "JS_WARM_ISOLATES must be a number of isolates".to_string())?,
+            cache_ttl: var_or("LAMBDA_CACHE_TTL_SECS", "30").parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|_| "LAMBDA_CACHE_TTL_SECS must be a number of seconds".to_string())?,
+            queue_size: var_or("LAMBDA_QUEUE_SIZE", "32").parse::<usize>()
+                .map_err(|_| "LAMBDA_QUEUE_SIZE must be a number of requests".to_string())?,
+            queue_wait: millis("LAMBDA_QUEUE_WAIT_MS", 5000)?,
+            http: HttpConfig {
+                timeout: millis("HTTP_TIMEOUT_MS", 30000)?,
+                connect_timeout: millis("HTTP_CONNECT_TIMEOUT_MS", 10000)?,
+                idle_timeout: var_or("HTTP_IDLE_TIMEOUT_MS", "90000").parse::<u64>()
+                    .map(Duration::from_millis)
+                    .map_err(|_| "HTTP_IDLE_TIMEOUT_MS must be a number of milliseconds".to_string())?,
+                user_agent: var_or("HTTP_USER_AGENT", concat!("serverless/", env!("CARGO_PKG_VERSION"))),
+                proxy: env::var("HTTP_PROXY").ok().filter(|p| !p.is_empty()),
+            },
+        })
+    }
+}
@@ -1,29 +1,196 @@
 use actix::prelude::*;
-use actix_web::*;
-use diesel;
-use diesel::prelude::*;
-use diesel::pg::PgConnection;
-use diesel::r2d2::{ConnectionManager, Pool};
 use actix::prelude::{Addr,Syn};
 
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cache::{Host, LambdaCache};
 use models;
-use schema;
 use request;
+use traffic;
+use router;
+use storage::{Storage};
+use error::Error;
+use concurrency::Limiter;
+use executor::{JsExecutor, Queue};
+use metrics::Metrics;
+use ratelimit::{KeySource, RateLimiter};
+use egress::{Egress, EgressPolicy};
+use runtime;
 
-pub struct DbExecutor(pub Pool<ConnectionManager<PgConnection>>);
+pub struct DbExecutor(pub Arc<Storage>, pub Arc<LambdaCache>);
 
 pub struct AppState {
     pub db: Addr<Syn, DbExecutor>,
+    pub js: Addr<Syn, JsExecutor>,
+    /// Invocations waiting for or running on `js`.
+    pub queue: Queue,
+    pub metrics: Arc<Metrics>,
+    /// Concurrency limits of lambdas and hostnames.
+    pub limiter: Arc<Limiter>,
+    /// Request rate limits of lambdas and hostnames.
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Bootstrap key with access to every hostname and to key management.
+    pub admin_key: Option<String>,
+    /// Execution limits for lambdas that do not set their own.
+    pub limits: runtime::Limits,
 }
 
 pub struct CreateLambda {
     pub path: String,
     pub hostname: String,
+    pub methods: Option<String>,
     pub code: String,
+    pub code_cache: Option<runtime::CodeCache>,
 }
 
+/// Routes an incoming request to the lambda and version that should serve it.
 pub struct GetLambda {
     pub request: request::Request,
+    pub stage: Option<String>,
+}
+
+/// Where `GetLambda` sends a request: the lambda version and the limits and
+/// egress policy of its hostname.
+pub struct Dispatch {
+    pub route: router::Match,
+    pub host_limits: models::HostLimits,
+    pub host_egress: Option<String>,
+}
+
+impl Dispatch {
+    /// The egress policies the lambda runs under.
+    pub fn egress(&self) -> Result<Egress, Error> {
+        Egress::stored(self.host_egress.as_ref().map(|p| p.as_str()),
+                       self.route.lambda.egress_policy.as_ref().map(|p| p.as_str()))
+            .map_err(Error::Internal)
+    }
+}
+
+/// Looks up a lambda by its exact registered path.
+pub struct FindLambda {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+}
+
+pub struct ListVersions {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+}
+
+pub struct RollbackLambda {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+    pub version: i32,
+}
+
+pub struct ListAliases {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+}
+
+pub struct SetAlias {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+    pub name: String,
+    pub version: i32,
+}
+
+pub struct DeleteAlias {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+    pub name: String,
+}
+
+pub struct GetTraffic {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+}
+
+pub struct SetTraffic {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+    pub config: request::TrafficConfig,
+}
+
+pub struct GetLimits {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+}
+
+pub struct SetLimits {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+    pub limits: models::LambdaLimits,
+}
+
+pub struct GetHostLimits {
+    pub hostname: String,
+}
+
+pub struct SetHostLimits {
+    pub limits: models::HostLimits,
+}
+
+pub struct GetEgress {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+}
+
+/// `None` removes the policy.
+pub struct SetEgress {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
+    pub policy: Option<EgressPolicy>,
+}
+
+pub struct GetHostEgress {
+    pub hostname: String,
+}
+
+/// `None` removes the policy.
+pub struct SetHostEgress {
+    pub hostname: String,
+    pub policy: Option<EgressPolicy>,
+}
+
+pub struct FindApiKey {
+    pub key_hash: String,
+}
+
+pub struct ListApiKeys;
+
+pub struct CreateApiKey {
+    pub name: String,
+    pub key_hash: String,
+    pub hostnames: String,
+}
+
+pub struct DeleteApiKey {
+    pub id: i32,
+}
+
+pub struct ListLambdas {
+    pub hostname: Option<String>,
+}
+
+pub struct DeleteLambda {
+    pub path: String,
+    pub hostname: String,
+    pub methods: Option<String>,
 }
 
 impl Message for CreateLambda {
@@ -31,9 +198,168 @@ impl Message for CreateLambda {
 }
 
 impl Message for GetLambda {
+    type Result = Result<Dispatch, Error>;
+}
+
+impl Message for FindLambda {
+    type Result = Result<models::Lambda, Error>;
+}
+
+impl Message for ListVersions {
+    type Result = Result<Vec<models::LambdaVersion>, Error>;
+}
+
+impl Message for RollbackLambda {
+    type Result = Result<models::Lambda, Error>;
+}
+
+impl Message for ListAliases {
+    type Result = Result<Vec<models::LambdaAlias>, Error>;
+}
+
+impl Message for SetAlias {
+    type Result = Result<models::LambdaAlias, Error>;
+}
+
+impl Message for DeleteAlias {
+    type Result = Result<models::LambdaAlias, Error>;
+}
+
+impl Message for GetTraffic {
+    type Result = Result<request::TrafficConfig, Error>;
+}
+
+impl Message for SetTraffic {
+    type Result = Result<request::TrafficConfig, Error>;
+}
+
+impl Message for GetLimits {
+    type Result = Result<models::LambdaLimits, Error>;
+}
+
+impl Message for SetLimits {
+    type Result = Result<models::LambdaLimits, Error>;
+}
+
+impl Message for GetHostLimits {
+    type Result = Result<models::HostLimits, Error>;
+}
+
+impl Message for SetHostLimits {
+    type Result = Result<models::HostLimits, Error>;
+}
+
+impl Message for GetEgress {
+    type Result = Result<Option<EgressPolicy>, Error>;
+}
+
+impl Message for SetEgress {
+    type Result = Result<Option<EgressPolicy>, Error>;
+}
+
+impl Message for GetHostEgress {
+    type Result = Result<Option<EgressPolicy>, Error>;
+}
+
+impl Message for SetHostEgress {
+    type Result = Result<Option<EgressPolicy>, Error>;
+}
+
+impl Message for FindApiKey {
+    type Result = Result<Option<models::ApiKey>, Error>;
+}
+
+impl Message for ListApiKeys {
+    type Result = Result<Vec<models::ApiKey>, Error>;
+}
+
+impl Message for CreateApiKey {
+    type Result = Result<models::ApiKey, Error>;
+}
+
+impl Message for DeleteApiKey {
+    type Result = Result<models::ApiKey, Error>;
+}
+
+impl Message for ListLambdas {
+    type Result = Result<Vec<models::Lambda>, Error>;
+}
+
+impl Message for DeleteLambda {
     type Result = Result<models::Lambda, Error>;
 }
 
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn normalize_methods(raw: &Option<String>) -> Result<String, Error> {
+    request::normalize_methods(raw.as_ref().map(|m| m.as_str()))
+        .map_err(Error::BadRequest)
+}
+
+fn find_lambda(storage: &Storage, path: &str, hostname: &str, methods: &Option<String>) -> Result<models::Lambda, Error> {
+    let methods = normalize_methods(methods)?;
+
+    storage.find_lambda(path, hostname, &methods)
+        .map_err(Error::from)?
+        .ok_or_else(|| Error::not_found("Not Found"))
+}
+
+fn find_version(storage: &Storage, lambda_id: i32, version: i32) -> Result<models::LambdaVersion, Error> {
+    storage.find_version(lambda_id, version)
+        .map_err(Error::from)?
+        .ok_or_else(|| Error::not_found("Version Not Found"))
+}
+
+fn load_traffic(storage: &Storage, lambda: &models::Lambda) -> Result<request::TrafficConfig, Error> {
+    let routes = storage.traffic(lambda.id)
+        .map_err(Error::from)?;
+
+    Ok(request::TrafficConfig {
+        sticky: lambda.sticky.clone(),
+        routes: routes.into_iter()
+            .map(|r| request::TrafficRoute { version: r.version, weight: r.weight })
+            .collect(),
+    })
+}
+
+fn load_host(storage: &Storage, hostname: &str) -> Result<Host, Error> {
+    let lambdas = storage.lambdas(Some(hostname))
+        .map_err(Error::from)?;
+
+    let mut traffic = HashMap::new();
+    let mut aliases = HashMap::new();
+    for lambda in lambdas.iter() {
+        traffic.insert(lambda.id, storage.traffic(lambda.id).map_err(Error::from)?);
+        aliases.insert(lambda.id, storage.aliases(lambda.id).map_err(Error::from)?);
+    }
+
+    let limits = storage.host_limits(hostname)
+        .map_err(Error::from)?
+        .unwrap_or_default();
+
+    let egress = storage.host_egress(hostname)
+        .map_err(Error::from)?
+        .map(|e| e.policy);
+
+    Ok(Host { routes: router::Routes::new(lambdas), traffic: traffic, aliases: aliases, limits: limits, egress: egress })
+}
+
+impl DbExecutor {
+    /// Drops `hostname` from this instance's cache and tells the others. The
+    /// change is already stored, so failing to notify is only logged.
+    fn changed(&self, hostname: &str) {
+        self.1.invalidate(hostname);
+        if let Err(e) = self.0.notify_change(hostname) {
+            warn!("Unable to notify other instances of a change to {}: {}", hostname, e);
+        }
+    }
+}
+
 impl Actor for DbExecutor {
     type Context = SyncContext<Self>;
 }
@@ -42,45 +368,408 @@ impl Handler<CreateLambda> for DbExecutor {
     type Result = Result<models::Lambda, Error>;
 
     fn handle(&mut self, msg: CreateLambda, _: &mut Self::Context) -> Self::Result {
-        use self::schema::lambdas::dsl::*;
-
-        let conn: &PgConnection = &self.0.get().unwrap(); 
-
+        let methods = normalize_methods(&msg.methods)?;
         let new_lambda = models::NewLambda {
             path: &msg.path,
             hostname: &msg.hostname,
+            methods: &methods,
             code: &msg.code,
+            code_cache: msg.code_cache.as_ref().map(|c| c.data.as_slice()),
+            code_cache_tag: msg.code_cache.as_ref().map(|c| c.tag.as_str()),
         };
 
-        let ret = diesel::insert_into(lambdas)
-            .values(&new_lambda)
-            .on_conflict((hostname, path))
-            .do_update()
-            .set(code.eq(new_lambda.code))
-            .get_result(conn)
-            .map_err(|_| error::ErrorInternalServerError("Error inserting lambda"))?;
+        let lambda = self.0.deploy(&new_lambda, now())
+            .map_err(Error::from)?;
 
-        Ok(ret)
+        self.changed(&lambda.hostname);
+        Ok(lambda)
     }
 }
 
 impl Handler<GetLambda> for DbExecutor {
-    type Result = Result<models::Lambda, Error>;
+    type Result = Result<Dispatch, Error>;
 
     fn handle(&mut self, msg: GetLambda, _: &mut Self::Context) -> Self::Result {
-        use self::schema::lambdas::dsl::*;
+        let hostname = msg.request.host();
+        let host =
+            match self.1.host(&hostname) {
+                Some(h) => h,
+                None => {
+                    let generation = self.1.generation(&hostname);
+                    self.1.put_host(&hostname, generation, load_host(&*self.0, &hostname)?)
+                },
+            };
+
+        let mut route = host.routes.find(&msg.request)?;
+
+        // a stage resolves through its alias, then the weighted traffic split,
+        // and finally the active version
+        let number =
+            match msg.stage {
+                Some(stage) => {
+                    host.aliases.get(&route.lambda.id)
+                        .and_then(|aliases| aliases.iter().find(|a| a.name == stage))
+                        .ok_or_else(|| Error::not_found("Stage Not Found"))?
+                        .version
+                },
+                None => {
+                    let routes = host.traffic.get(&route.lambda.id)
+                        .map(|r| r.as_slice())
+                        .unwrap_or(&[]);
+
+                    let sticky = route.lambda.sticky.as_ref().map(|s| s.as_str());
+                    traffic::pick(routes, sticky, &msg.request).unwrap_or(route.lambda.version)
+                },
+            };
+
+        // the active version's code comes with the lambda
+        if number != route.lambda.version {
+            let resolved =
+                match self.1.version(route.lambda.id, number) {
+                    Some(v) => v,
+                    None => {
+                        let v = find_version(&*self.0, route.lambda.id, number)?;
+                        self.1.put_version(&v);
+                        v
+                    },
+                };
+            route.lambda.code = resolved.code;
+            route.lambda.version = resolved.version;
+            route.lambda.code_cache = resolved.code_cache;
+            route.lambda.code_cache_tag = resolved.code_cache_tag;
+        }
+
+        Ok(Dispatch { route: route, host_limits: host.limits.clone(), host_egress: host.egress.clone() })
+    }
+}
+
+impl Handler<FindLambda> for DbExecutor {
+    type Result = Result<models::Lambda, Error>;
+
+    fn handle(&mut self, msg: FindLambda, _: &mut Self::Context) -> Self::Result {
+        find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)
+    }
+}
+
+impl Handler<ListLambdas> for DbExecutor {
+    type Result = Result<Vec<models::Lambda>, Error>;
+
+    fn handle(&mut self, msg: ListLambdas, _: &mut Self::Context) -> Self::Result {
+        self.0.lambdas(msg.hostname.as_ref().map(|h| h.as_str()))
+            .map_err(Error::from)
+    }
+}
+
+impl Handler<DeleteLambda> for DbExecutor {
+    type Result = Result<models::Lambda, Error>;
+
+    fn handle(&mut self, msg: DeleteLambda, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+
+        self.0.delete_lambda(lambda.id)
+            .map_err(Error::from)?;
+
+        self.changed(&lambda.hostname);
+        Ok(lambda)
+    }
+}
+
+impl Handler<ListVersions> for DbExecutor {
+    type Result = Result<Vec<models::LambdaVersion>, Error>;
+
+    fn handle(&mut self, msg: ListVersions, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+
+        self.0.versions(lambda.id)
+            .map_err(Error::from)
+    }
+}
+
+impl Handler<RollbackLambda> for DbExecutor {
+    type Result = Result<models::Lambda, Error>;
+
+    fn handle(&mut self, msg: RollbackLambda, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+        let target = find_version(&*self.0, lambda.id, msg.version)?;
+
+        let lambda = self.0.activate(lambda.id, &target)
+            .map_err(Error::from)?;
+
+        self.changed(&lambda.hostname);
+        Ok(lambda)
+    }
+}
+
+impl Handler<ListAliases> for DbExecutor {
+    type Result = Result<Vec<models::LambdaAlias>, Error>;
+
+    fn handle(&mut self, msg: ListAliases, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+
+        self.0.aliases(lambda.id)
+            .map_err(Error::from)
+    }
+}
+
+impl Handler<SetAlias> for DbExecutor {
+    type Result = Result<models::LambdaAlias, Error>;
+
+    fn handle(&mut self, msg: SetAlias, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+        // aliases may only point at versions that were actually deployed
+        let target = find_version(&*self.0, lambda.id, msg.version)?;
+
+        let new_alias = models::NewLambdaAlias {
+            lambda_id: lambda.id,
+            name: &msg.name,
+            version: target.version,
+        };
+
+        let alias = self.0.set_alias(&new_alias)
+            .map_err(Error::from)?;
+
+        self.changed(&lambda.hostname);
+        Ok(alias)
+    }
+}
+
+impl Handler<DeleteAlias> for DbExecutor {
+    type Result = Result<models::LambdaAlias, Error>;
+
+    fn handle(&mut self, msg: DeleteAlias, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+
+        let alias = self.0.find_alias(lambda.id, &msg.name)
+            .map_err(Error::from)?
+            .ok_or_else(|| Error::not_found("Not Found"))?;
+
+        self.0.delete_alias(alias.id)
+            .map_err(Error::from)?;
+
+        self.changed(&lambda.hostname);
+        Ok(alias)
+    }
+}
+
+impl Handler<GetTraffic> for DbExecutor {
+    type Result = Result<request::TrafficConfig, Error>;
+
+    fn handle(&mut self, msg: GetTraffic, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+        load_traffic(&*self.0, &lambda)
+    }
+}
+
+impl Handler<SetTraffic> for DbExecutor {
+    type Result = Result<request::TrafficConfig, Error>;
+
+    fn handle(&mut self, msg: SetTraffic, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+
+        if let Some(ref sticky) = msg.config.sticky {
+            if !traffic::valid_sticky(sticky) {
+                return Err(Error::BadRequest("Sticky must be header:<name> or cookie:<name>".to_string()));
+            }
+        }
+        for route in msg.config.routes.iter() {
+            if route.weight < 0 {
+                return Err(Error::BadRequest("Weights must not be negative".to_string()));
+            }
+            find_version(&*self.0, lambda.id, route.version)?;
+        }
+
+        let routes: Vec<models::NewLambdaTraffic> = msg.config.routes.iter()
+            .map(|r| models::NewLambdaTraffic { lambda_id: lambda.id, version: r.version, weight: r.weight })
+            .collect();
+        let sticky = msg.config.sticky.as_ref().map(|s| s.as_str());
 
-        let conn: &PgConnection = &self.0.get().unwrap();
+        let lambda = self.0.set_traffic(lambda.id, sticky, &routes)
+            .map_err(Error::from)?;
 
-        let mut items = lambdas
-            .filter(path.eq(msg.request.path()))
-            .filter(hostname.eq(msg.request.host()))
-            .load::<models::Lambda>(conn)
-            .map_err(|_| error::ErrorInternalServerError("Error loading lambda"))?;
+        self.changed(&lambda.hostname);
+        load_traffic(&*self.0, &lambda)
+    }
+}
+
+impl Handler<GetLimits> for DbExecutor {
+    type Result = Result<models::LambdaLimits, Error>;
+
+    fn handle(&mut self, msg: GetLimits, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+        Ok(lambda.limits())
+    }
+}
+
+fn check_rate_limit_key(key: &Option<String>) -> Result<(), Error> {
+    match *key {
+        Some(ref k) if KeySource::parse(k).is_none() =>
+            Err(Error::BadRequest("Rate limit key must be ip, header:<name> or script".to_string())),
+        _ => Ok(()),
+    }
+}
+
+impl Handler<SetLimits> for DbExecutor {
+    type Result = Result<models::LambdaLimits, Error>;
+
+    fn handle(&mut self, msg: SetLimits, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+
+        let limits = [msg.limits.timeout_ms, msg.limits.cpu_timeout_ms, msg.limits.heap_limit_mb,
+                      msg.limits.max_concurrency, msg.limits.rate_limit];
+        if limits.iter().any(|l| l.map(|ms| ms <= 0).unwrap_or(false)) {
+            return Err(Error::BadRequest("Limits must be positive".to_string()));
+        }
+        check_rate_limit_key(&msg.limits.rate_limit_key)?;
+
+        let lambda = self.0.set_limits(lambda.id, &msg.limits)
+            .map_err(Error::from)?;
+
+        self.changed(&lambda.hostname);
+        Ok(lambda.limits())
+    }
+}
+
+impl Handler<GetHostLimits> for DbExecutor {
+    type Result = Result<models::HostLimits, Error>;
 
-        match items.pop() {
-            Some(i) => Ok(i),
-            None => Err(error::ErrorNotFound("Not Found")),
+    fn handle(&mut self, msg: GetHostLimits, _: &mut Self::Context) -> Self::Result {
+        let limits = self.0.host_limits(&msg.hostname)
+            .map_err(Error::from)?;
+
+        Ok(limits.unwrap_or_else(|| models::HostLimits { hostname: msg.hostname, ..Default::default() }))
+    }
+}
+
+impl Handler<SetHostLimits> for DbExecutor {
+    type Result = Result<models::HostLimits, Error>;
+
+    fn handle(&mut self, msg: SetHostLimits, _: &mut Self::Context) -> Self::Result {
+        let limits = [msg.limits.max_concurrency, msg.limits.rate_limit];
+        if limits.iter().any(|l| l.map(|m| m <= 0).unwrap_or(false)) {
+            return Err(Error::BadRequest("Limits must be positive".to_string()));
         }
+        check_rate_limit_key(&msg.limits.rate_limit_key)?;
+
+        let limits = self.0.set_host_limits(&msg.limits)
+            .map_err(Error::from)?;
+
+        self.changed(&limits.hostname);
+        Ok(limits)
+    }
+}
+
+fn stored_policy(raw: Option<&str>) -> Result<Option<EgressPolicy>, Error> {
+    match raw {
+        Some(p) => EgressPolicy::parse(p).map(Some).map_err(Error::Internal),
+        None => Ok(None),
+    }
+}
+
+fn policy_json(policy: &Option<EgressPolicy>) -> Result<Option<String>, Error> {
+    match *policy {
+        Some(ref p) => {
+            p.validate().map_err(Error::BadRequest)?;
+            Ok(Some(p.to_json()))
+        },
+        None => Ok(None),
+    }
+}
+
+impl Handler<GetEgress> for DbExecutor {
+    type Result = Result<Option<EgressPolicy>, Error>;
+
+    fn handle(&mut self, msg: GetEgress, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+        stored_policy(lambda.egress_policy.as_ref().map(|p| p.as_str()))
+    }
+}
+
+impl Handler<SetEgress> for DbExecutor {
+    type Result = Result<Option<EgressPolicy>, Error>;
+
+    fn handle(&mut self, msg: SetEgress, _: &mut Self::Context) -> Self::Result {
+        let lambda = find_lambda(&*self.0, &msg.path, &msg.hostname, &msg.methods)?;
+        let policy = policy_json(&msg.policy)?;
+
+        let lambda = self.0.set_egress(lambda.id, policy.as_ref().map(|p| p.as_str()))
+            .map_err(Error::from)?;
+
+        self.changed(&lambda.hostname);
+        stored_policy(lambda.egress_policy.as_ref().map(|p| p.as_str()))
+    }
+}
+
+impl Handler<GetHostEgress> for DbExecutor {
+    type Result = Result<Option<EgressPolicy>, Error>;
+
+    fn handle(&mut self, msg: GetHostEgress, _: &mut Self::Context) -> Self::Result {
+        let egress = self.0.host_egress(&msg.hostname)
+            .map_err(Error::from)?;
+        stored_policy(egress.as_ref().map(|e| e.policy.as_str()))
+    }
+}
+
+impl Handler<SetHostEgress> for DbExecutor {
+    type Result = Result<Option<EgressPolicy>, Error>;
+
+    fn handle(&mut self, msg: SetHostEgress, _: &mut Self::Context) -> Self::Result {
+        let policy = policy_json(&msg.policy)?;
+
+        let egress = self.0.set_host_egress(&msg.hostname, policy.as_ref().map(|p| p.as_str()))
+            .map_err(Error::from)?;
+
+        self.changed(&msg.hostname);
+        stored_policy(egress.as_ref().map(|e| e.policy.as_str()))
+    }
+}
+
+impl Handler<FindApiKey> for DbExecutor {
+    type Result = Result<Option<models::ApiKey>, Error>;
+
+    fn handle(&mut self, msg: FindApiKey, _: &mut Self::Context) -> Self::Result {
+        self.0.find_api_key(&msg.key_hash)
+            .map_err(Error::from)
+    }
+}
+
+impl Handler<ListApiKeys> for DbExecutor {
+    type Result = Result<Vec<models::ApiKey>, Error>;
+
+    fn handle(&mut self, _: ListApiKeys, _: &mut Self::Context) -> Self::Result {
+        self.0.api_keys()
+            .map_err(Error::from)
+    }
+}
+
+impl Handler<CreateApiKey> for DbExecutor {
+    type Result = Result<models::ApiKey, Error>;
+
+    fn handle(&mut self, msg: CreateApiKey, _: &mut Self::Context) -> Self::Result {
+        let new_key = models::NewApiKey {
+            name: &msg.name,
+            key_hash: &msg.key_hash,
+            hostnames: &msg.hostnames,
+            created_at: now(),
+        };
+
+        self.0.create_api_key(&new_key)
+            .map_err(Error::from)
     }
-}
\ No newline at end of file
+}
+
+impl Handler<DeleteApiKey> for DbExecutor {
+    type Result = Result<models::ApiKey, Error>;
+
+    fn handle(&mut self, msg: DeleteApiKey, _: &mut Self::Context) -> Self::Result {
+        let key = self.0.api_keys()
+            .map_err(Error::from)?
+            .into_iter()
+            .find(|k| k.id == msg.id)
+            .ok_or_else(|| Error::not_found("Not Found"))?;
+
+        self.0.delete_api_key(key.id)
+            .map_err(Error::from)?;
+
+        Ok(key)
+    }
+}
@@ -0,0 +1,310 @@
+extern crate serde_json;
+extern crate url;
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use self::url::{Host, Url};
+
+/// Address ranges lambdas may only reach when a policy allows them
+/// explicitly: loopback, private, link-local (cloud metadata services),
+/// shared, multicast and reserved addresses, and the IPv6 ranges that embed
+/// an IPv4 address (IPv4-compatible, NAT64 and 6to4).
+const PRIVATE_RANGES: &[&str] = &[
+    "0.0.0.0/8", "10.0.0.0/8", "100.64.0.0/10", "127.0.0.0/8", "169.254.0.0/16",
+    "172.16.0.0/12", "192.0.0.0/24", "192.168.0.0/16", "198.18.0.0/15", "224.0.0.0/4",
+    "240.0.0.0/4", "::/96", "64:ff9b::/96", "64:ff9b:1::/48", "2002::/16", "fc00::/7",
+    "fe80::/10", "ff00::/8",
+];
+
+/// Schemes allowed when a policy does not list its own.
+const DEFAULT_SCHEMES: &[&str] = &["http", "https"];
+
+/// Where the outbound requests of a lambda may go. Deny lists win over allow
+/// lists, an empty list of allowed domains allows every domain not denied.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EgressPolicy {
+    /// Domains that may be called, `example.com` includes its subdomains.
+    #[serde(default)]
+    pub allow_domains: Vec<String>,
+    #[serde(default)]
+    pub deny_domains: Vec<String>,
+    /// Private ranges that may be called all the same, e.g. `10.1.0.0/16`.
+    #[serde(default)]
+    pub allow_cidrs: Vec<String>,
+    #[serde(default)]
+    pub deny_cidrs: Vec<String>,
+    /// Ports that may be called, any when empty.
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    /// `http` and `https` when empty.
+    #[serde(default)]
+    pub schemes: Vec<String>,
+}
+
+/// An address range, `address/prefix`.
+#[derive(Clone, Copy, Debug)]
+struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn parse(raw: &str) -> Option<Cidr> {
+        let mut parts = raw.trim().splitn(2, '/');
+        let address: IpAddr = parts.next()?.parse().ok()?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix =
+            match parts.next() {
+                Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max)?,
+                None => max,
+            };
+        Some(Cidr { address: address, prefix: prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { !0u32 << (32 - self.prefix) };
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { !0u128 << (128 - self.prefix) };
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+fn any_contains(cidrs: &[String], ip: IpAddr) -> bool {
+    cidrs.iter().filter_map(|c| Cidr::parse(c)).any(|c| c.contains(ip))
+}
+
+fn matches_domain(pattern: &str, domain: &str) -> bool {
+    let pattern = pattern.trim().trim_left_matches("*.").trim_right_matches('.').to_lowercase();
+    let domain = domain.trim_right_matches('.').to_lowercase();
+    domain == pattern || domain.ends_with(&format!(".{}", pattern))
+}
+
+/// IPv4 addresses mapped into IPv6 are checked as what they are.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            if s[0..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+                IpAddr::V4(Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8))
+            } else {
+                IpAddr::V6(v6)
+            }
+        },
+        v4 => v4,
+    }
+}
+
+impl EgressPolicy {
+    /// Parses a stored policy.
+    pub fn parse(raw: &str) -> Result<EgressPolicy, String> {
+        serde_json::from_str(raw).map_err(|e| format!("Invalid egress policy: {}", e))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string())
+    }
+
+    /// Rejects policies that could never be enforced as written.
+    pub fn validate(&self) -> Result<(), String> {
+        for cidr in self.allow_cidrs.iter().chain(self.deny_cidrs.iter()) {
+            if Cidr::parse(cidr).is_none() {
+                return Err(format!("Invalid CIDR {:?}", cidr));
+            }
+        }
+        for domain in self.allow_domains.iter().chain(self.deny_domains.iter()) {
+            if domain.trim().trim_left_matches("*.").is_empty() {
+                return Err("Domains must not be empty".to_string());
+            }
+        }
+        for scheme in self.schemes.iter() {
+            if scheme != "http" && scheme != "https" {
+                return Err(format!("Unsupported scheme {:?}, only http and https can be called", scheme));
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, url: &Url, domain: Option<&str>) -> Result<(), String> {
+        if !self.schemes.is_empty() && !self.schemes.iter().any(|s| s == url.scheme()) {
+            return Err(format!("scheme {} is not allowed", url.scheme()));
+        }
+        if let Some(port) = url.port_or_known_default() {
+            if !self.ports.is_empty() && !self.ports.contains(&port) {
+                return Err(format!("port {} is not allowed", port));
+            }
+        }
+
+        if let Some(domain) = domain {
+            if self.deny_domains.iter().any(|d| matches_domain(d, domain)) {
+                return Err(format!("{} is denied", domain));
+            }
+        }
+        if !self.allow_domains.is_empty() {
+            let allowed = domain.map(|d| self.allow_domains.iter().any(|p| matches_domain(p, d))).unwrap_or(false);
+            if !allowed {
+                return Err(format!("{} is not an allowed domain", url.host_str().unwrap_or("")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The policies a lambda runs under, the hostname's and its own. A call has
+/// to pass both.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Egress {
+    pub host: Option<EgressPolicy>,
+    pub lambda: Option<EgressPolicy>,
+}
+
+impl Egress {
+    /// From the stored policies of a hostname and a lambda.
+    pub fn stored(host: Option<&str>, lambda: Option<&str>) -> Result<Egress, String> {
+        Ok(Egress {
+            host: match host { Some(p) => Some(EgressPolicy::parse(p)?), None => None },
+            lambda: match lambda { Some(p) => Some(EgressPolicy::parse(p)?), None => None },
+        })
+    }
+
+    fn policies(&self) -> Vec<&EgressPolicy> {
+        self.host.iter().chain(self.lambda.iter()).collect()
+    }
+
+    /// Checks the scheme, port and domain of `url` against the policies. The
+    /// addresses its host resolves to are checked by `check_addresses` as
+    /// the connection is opened.
+    pub fn check(&self, url: &Url) -> Result<(), String> {
+        let policies = self.policies();
+        if policies.iter().all(|p| p.schemes.is_empty()) && !DEFAULT_SCHEMES.contains(&url.scheme()) {
+            return Err(format!("scheme {} is not allowed", url.scheme()));
+        }
+
+        let domain =
+            match url.host() {
+                Some(Host::Domain(d)) => Some(d),
+                Some(_) => None,
+                None => return Err("the URL has no host".to_string()),
+            };
+        for policy in policies.iter() {
+            policy.check(url, domain)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the addresses a host resolved to, every one of them has to
+    /// pass so a name can not alternate between a public and a private one.
+    pub fn check_addresses(&self, addresses: &[IpAddr]) -> Result<(), String> {
+        let policies = self.policies();
+        for ip in addresses.iter().map(|ip| canonical(*ip)) {
+            if policies.iter().any(|p| any_contains(&p.deny_cidrs, ip)) {
+                return Err(format!("{} is denied", ip));
+            }
+            let private = PRIVATE_RANGES.iter().filter_map(|r| Cidr::parse(r)).any(|c| c.contains(ip));
+            if private && !policies.iter().any(|p| any_contains(&p.allow_cidrs, ip)) {
+                return Err(format!("{} is a private address", ip));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(raw: &str) -> IpAddr {
+        raw.parse().unwrap()
+    }
+
+    fn url(raw: &str) -> Url {
+        Url::parse(raw).unwrap()
+    }
+
+    fn lambda(policy: EgressPolicy) -> Egress {
+        Egress { host: None, lambda: Some(policy) }
+    }
+
+    #[test]
+    fn cidrs_contain_their_addresses() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(ip("10.1.255.1")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(!net.contains(ip("::ffff:10.1.0.1")));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(Cidr::parse("192.0.2.1").unwrap().contains(ip("192.0.2.1")));
+        assert!(!Cidr::parse("192.0.2.1").unwrap().contains(ip("192.0.2.2")));
+        assert!(Cidr::parse("fe80::/10").unwrap().contains(ip("fe80::1")));
+        assert!(!Cidr::parse("fe80::/10").unwrap().contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn rejects_invalid_cidrs() {
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+        assert!(Cidr::parse("::/129").is_none());
+        assert!(Cidr::parse("example.com/8").is_none());
+        assert!(EgressPolicy { allow_cidrs: vec!["10.0.0.0/x".to_string()], ..Default::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn domains_include_subdomains() {
+        assert!(matches_domain("example.com", "example.com"));
+        assert!(matches_domain("example.com", "api.example.com"));
+        assert!(matches_domain("*.example.com", "api.example.com"));
+        assert!(matches_domain("Example.com.", "API.example.com"));
+        assert!(!matches_domain("example.com", "badexample.com"));
+        assert!(!matches_domain("api.example.com", "example.com"));
+    }
+
+    #[test]
+    fn checks_domains_ports_and_schemes() {
+        let egress = lambda(EgressPolicy {
+            allow_domains: vec!["*.example.com".to_string()],
+            deny_domains: vec!["internal.example.com".to_string()],
+            ports: vec![443],
+            schemes: vec!["https".to_string()],
+            ..Default::default()
+        });
+        assert!(egress.check(&url("https://api.example.com/")).is_ok());
+        assert!(egress.check(&url("https://db.internal.example.com/")).is_err());
+        assert!(egress.check(&url("https://example.org/")).is_err());
+        assert!(egress.check(&url("https://api.example.com:8443/")).is_err());
+        assert!(egress.check(&url("http://api.example.com:443/")).is_err());
+        // an allow list of domains leaves out bare addresses
+        assert!(egress.check(&url("https://93.184.216.34/")).is_err());
+
+        assert!(Egress::default().check(&url("ftp://example.com/")).is_err());
+        assert!(Egress::default().check(&url("http://example.com/")).is_ok());
+    }
+
+    #[test]
+    fn refuses_private_addresses() {
+        let egress = Egress::default();
+        for private in &["127.0.0.1", "10.0.0.1", "169.254.169.254", "::1", "fd00::1", "::ffff:127.0.0.1",
+                         "::127.0.0.1", "64:ff9b::a9fe:a9fe", "2002:a9fe:a9fe::1"] {
+            assert!(egress.check_addresses(&[ip(private)]).is_err(), "{} passed", private);
+        }
+        assert!(egress.check_addresses(&[ip("93.184.216.34"), ip("2606:2800:220:1::1")]).is_ok());
+        // every address has to pass
+        assert!(egress.check_addresses(&[ip("93.184.216.34"), ip("10.0.0.1")]).is_err());
+    }
+
+    #[test]
+    fn policies_allow_and_deny_ranges() {
+        let egress = Egress {
+            host: Some(EgressPolicy { allow_cidrs: vec!["10.1.0.0/16".to_string()], ..Default::default() }),
+            lambda: Some(EgressPolicy { deny_cidrs: vec!["93.184.0.0/16".to_string()], ..Default::default() }),
+        };
+        assert!(egress.check_addresses(&[ip("10.1.2.3")]).is_ok());
+        assert!(egress.check_addresses(&[ip("::ffff:10.1.2.3")]).is_ok());
+        assert!(egress.check_addresses(&[ip("10.2.0.1")]).is_err());
+        assert!(egress.check_addresses(&[ip("93.184.216.34")]).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use actix::MailboxError;
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+
+use router::{RouteError};
+use runtime::{CompileError};
+use storage::{StorageError};
+
+/// Everything that can go wrong while serving a request. Each variant maps to
+/// one HTTP status and is rendered as a JSON body by `error_response`.
+#[derive(Debug, Fail)]
+pub enum Error {
+    /// No lambda, version, alias or key matches the request.
+    #[fail(display = "{}", _0)]
+    NotFound(String),
+    /// The path exists but not for this method, carries the allowed methods.
+    #[fail(display = "Method Not Allowed")]
+    MethodNotAllowed(Vec<String>),
+    /// Invalid input to the admin API.
+    #[fail(display = "{}", _0)]
+    BadRequest(String),
+    /// A concurrent change to the same record won, the request can be retried.
+    #[fail(display = "{}", _0)]
+    Conflict(String),
+    #[fail(display = "{}", _0)]
+    Storage(#[cause] StorageError),
+    /// Source rejected at deploy time.
+    #[fail(display = "{}", _0)]
+    Compile(CompileError),
+    /// The lambda failed while running, e.g. it threw an exception.
+    #[fail(display = "{}", _0)]
+    Runtime(String),
+    /// The lambda ran past its time limit and was terminated.
+    #[fail(display = "{}", _0)]
+    Timeout(String),
+    /// The lambda ran out of heap and was terminated.
+    #[fail(display = "{}", _0)]
+    HeapLimit(String),
+    /// The lambda or hostname is at its concurrency limit and the queue in
+    /// front of it is full, or the request waited too long.
+    #[fail(display = "{}", message)]
+    Throttled { message: String, retry_after: u64 },
+    /// Every JavaScript executor is busy and the queue in front of them is full.
+    #[fail(display = "{}", _0)]
+    Overloaded(String),
+    /// A value could not be converted between Rust and JavaScript, e.g. an
+    /// invalid `response.status`.
+    #[fail(display = "{}", _0)]
+    Bridge(String),
+    /// The executor behind a message went away.
+    #[fail(display = "{}", _0)]
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow: Option<&'a Vec<String>>,
+}
+
+impl Error {
+    pub fn not_found(message: &str) -> Error {
+        Error::NotFound(message.to_string())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Error::BadRequest(_) | Error::Compile(_) => StatusCode::BAD_REQUEST,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::Throttled { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Storage(_) | Error::Runtime(_) | Error::HeapLimit(_) | Error::Bridge(_) | Error::Internal(_) =>
+                StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Error::NotFound(_) => "not_found",
+            Error::MethodNotAllowed(_) => "method_not_allowed",
+            Error::BadRequest(_) => "bad_request",
+            Error::Conflict(_) => "conflict",
+            Error::Storage(_) => "storage",
+            Error::Compile(_) => "compile",
+            Error::Runtime(_) => "runtime",
+            Error::Timeout(_) => "timeout",
+            Error::HeapLimit(_) => "heap_limit",
+            Error::Throttled { .. } => "throttled",
+            Error::Overloaded(_) => "overloaded",
+            Error::Bridge(_) => "bridge",
+            Error::Internal(_) => "internal",
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status();
+        if status.is_server_error() {
+            error!("{}: {}", self.kind(), self);
+        }
+
+        // storage details stay in the log, they are of no use to the caller
+        let message =
+            match self {
+                Error::Storage(_) => "Storage Error".to_string(),
+                _ => self.to_string(),
+            };
+        let (line, column) =
+            match self {
+                Error::Compile(e) => (e.line, e.column),
+                _ => (None, None),
+            };
+        let allow =
+            match self {
+                Error::MethodNotAllowed(methods) => Some(methods),
+                _ => None,
+            };
+
+        let mut resp = HttpResponse::build(status);
+        if let Some(methods) = allow {
+            resp.header("Allow", methods.join(", ").as_str());
+        }
+        if let Error::Throttled { retry_after, .. } = self {
+            resp.header("Retry-After", retry_after.to_string().as_str());
+        }
+        resp.json(ErrorBody {
+            error: self.kind(),
+            message: message,
+            line: line,
+            column: column,
+            allow: allow,
+        })
+    }
+}
+
+impl From<RouteError> for Error {
+    fn from(e: RouteError) -> Error {
+        match e {
+            RouteError::NotFound => Error::not_found("Not Found"),
+            RouteError::MethodNotAllowed(allowed) => Error::MethodNotAllowed(allowed),
+        }
+    }
+}
+
+impl From<StorageError> for Error {
+    fn from(e: StorageError) -> Error {
+        match e {
+            StorageError::Conflict(_) => Error::Conflict("Changed concurrently, retry the request".to_string()),
+            e => Error::Storage(e),
+        }
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(e: CompileError) -> Error {
+        Error::Compile(e)
+    }
+}
+
+impl From<MailboxError> for Error {
+    fn from(e: MailboxError) -> Error {
+        Error::Internal(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_variants_to_statuses() {
+        let compile = CompileError { error: "SyntaxError".to_string(), line: Some(1), column: Some(2) };
+        let cases = vec![
+            (Error::not_found("Not Found"), StatusCode::NOT_FOUND, "not_found"),
+            (Error::MethodNotAllowed(vec!["GET".to_string()]), StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed"),
+            (Error::BadRequest("bad".to_string()), StatusCode::BAD_REQUEST, "bad_request"),
+            (Error::Compile(compile), StatusCode::BAD_REQUEST, "compile"),
+            (Error::Conflict("retry".to_string()), StatusCode::CONFLICT, "conflict"),
+            (Error::Runtime("threw".to_string()), StatusCode::INTERNAL_SERVER_ERROR, "runtime"),
+            (Error::Timeout("slow".to_string()), StatusCode::GATEWAY_TIMEOUT, "timeout"),
+            (Error::HeapLimit("big".to_string()), StatusCode::INTERNAL_SERVER_ERROR, "heap_limit"),
+            (Error::Throttled { message: "slow down".to_string(), retry_after: 3 }, StatusCode::TOO_MANY_REQUESTS, "throttled"),
+            (Error::Overloaded("busy".to_string()), StatusCode::SERVICE_UNAVAILABLE, "overloaded"),
+            (Error::Bridge("status".to_string()), StatusCode::INTERNAL_SERVER_ERROR, "bridge"),
+            (Error::Internal("gone".to_string()), StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+        ];
+        for (error, status, kind) in cases {
+            assert_eq!(error.status(), status, "{}", kind);
+            assert_eq!(error.kind(), kind);
+            assert_eq!(error.error_response().status(), status, "{}", kind);
+        }
+    }
+
+    #[test]
+    fn route_errors_keep_allowed_methods() {
+        let error = Error::from(RouteError::MethodNotAllowed(vec!["GET".to_string(), "POST".to_string()]));
+        let resp = error.error_response();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers().get("Allow").unwrap(), "GET, POST");
+
+        assert_eq!(Error::from(RouteError::NotFound).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn throttled_sets_retry_after() {
+        let resp = Error::Throttled { message: "slow down".to_string(), retry_after: 7 }.error_response();
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "7");
+    }
+}
@@ -0,0 +1,133 @@
+use actix::prelude::*;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use egress::Egress;
+use error::Error;
+use request::Request;
+use response::Response;
+use runtime::{self, IsolatePool, Script};
+
+/// Runs lambdas off the HTTP workers, started as a `SyncArbiter` pool. Each
+/// executor keeps its own warm isolates.
+pub struct JsExecutor(pub IsolatePool);
+
+// Isolates are bound to the thread that created them. `SyncArbiter` requires
+// `Send` but builds every executor with its factory on the executor's own
+// thread, where it stays until it is dropped.
+unsafe impl Send for JsExecutor {}
+
+/// Compiles a lambda's code before it is deployed, see
+/// `IsolatePool::validate`.
+pub struct Validate {
+    pub code: String,
+    pub limits: runtime::Limits,
+}
+
+impl Message for Validate {
+    type Result = Result<Option<runtime::CodeCache>, Error>;
+}
+
+/// Invokes a lambda version's `handler` with `request`.
+pub struct Execute {
+    pub lambda_id: i32,
+    pub version: i32,
+    pub code: String,
+    pub code_cache: Option<runtime::CodeCache>,
+    pub request: Request,
+    pub limits: runtime::Limits,
+    pub egress: Egress,
+}
+
+impl Message for Execute {
+    type Result = Result<Response, Error>;
+}
+
+/// Asks a lambda version for the key its callers are rate limited by.
+pub struct RateLimitKey {
+    pub lambda_id: i32,
+    pub version: i32,
+    pub code: String,
+    pub code_cache: Option<runtime::CodeCache>,
+    pub request: Request,
+    pub limits: runtime::Limits,
+    pub egress: Egress,
+}
+
+impl Message for RateLimitKey {
+    type Result = Result<Option<String>, Error>;
+}
+
+impl Actor for JsExecutor {
+    type Context = SyncContext<Self>;
+}
+
+impl Handler<Validate> for JsExecutor {
+    type Result = Result<Option<runtime::CodeCache>, Error>;
+
+    fn handle(&mut self, msg: Validate, _: &mut Self::Context) -> Self::Result {
+        self.0.validate(&msg.code, msg.limits).map_err(Error::Compile)
+    }
+}
+
+impl Handler<Execute> for JsExecutor {
+    type Result = Result<Response, Error>;
+
+    fn handle(&mut self, msg: Execute, _: &mut Self::Context) -> Self::Result {
+        let script = Script {
+            lambda_id: msg.lambda_id,
+            version: msg.version,
+            code: &msg.code,
+            cache: msg.code_cache.as_ref(),
+            egress: &msg.egress,
+        };
+        self.0.execute(&script, &msg.request, msg.limits)
+    }
+}
+
+impl Handler<RateLimitKey> for JsExecutor {
+    type Result = Result<Option<String>, Error>;
+
+    fn handle(&mut self, msg: RateLimitKey, _: &mut Self::Context) -> Self::Result {
+        let script = Script {
+            lambda_id: msg.lambda_id,
+            version: msg.version,
+            code: &msg.code,
+            cache: msg.code_cache.as_ref(),
+            egress: &msg.egress,
+        };
+        self.0.rate_limit_key(&script, &msg.request, msg.limits)
+    }
+}
+
+/// Bounds the invocations that are running or waiting for an executor. The
+/// mailbox of a `SyncArbiter` is unbounded, so this is checked before sending.
+#[derive(Clone)]
+pub struct Queue {
+    pending: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+/// A place in the `Queue`, given back when dropped.
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Queue {
+    pub fn new(capacity: usize) -> Queue {
+        Queue { pending: Arc::new(AtomicUsize::new(0)), capacity: capacity }
+    }
+
+    pub fn acquire(&self) -> Result<Slot, Error> {
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= self.capacity {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::Overloaded("Too many pending invocations".to_string()));
+        }
+        Ok(Slot(self.pending.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
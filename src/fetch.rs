@@ -0,0 +1,331 @@
+extern crate hyper;
+extern crate tokio_core;
+extern crate v8;
+
+use futures::{future, Future, Stream};
+use futures::future::{Either, Loop};
+use futures::sync::oneshot;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use self::hyper::client::Client;
+use self::hyper::header::{Location, UserAgent};
+use self::tokio_core::reactor::{Core, Handle, Timeout};
+
+use egress::Egress;
+use headers::Header;
+use outbound::{self, Connector, HttpClients};
+use request::Request;
+
+/// A response received by `fetch()` or `http.request`.
+pub struct Fetched {
+    pub status: u16,
+    pub status_text: String,
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+}
+
+impl Fetched {
+    /// The response as the prelude expects it, the body both as text and as
+    /// a string of char codes below 256 for `arrayBuffer()`.
+    pub fn js(&self, isolate: &v8::isolate::Isolate, context: &v8::context::Context) -> v8::value::Object {
+        let ret = v8::value::Object::new(&isolate, &context);
+        ret.set(&context, &v8::value::String::from_str(&isolate, "status"),
+            &v8::value::Integer::new(&isolate, self.status as i32));
+        ret.set(&context, &v8::value::String::from_str(&isolate, "statusText"),
+            &v8::value::String::from_str(&isolate, self.status_text.as_str()));
+
+        let headers = v8::value::Array::new(&isolate, &context, 0);
+        let mut count = 0;
+        for h in self.headers.iter() {
+            headers.set(&context, &v8::value::Integer::new(&isolate, count), &h.js(&isolate, &context));
+            count += 1;
+        }
+        ret.set(&context, &v8::value::String::from_str(&isolate, "headers"), &headers);
+
+        let text = String::from_utf8_lossy(&self.body);
+        ret.set(&context, &v8::value::String::from_str(&isolate, "text"),
+            &v8::value::String::from_str(&isolate, &text));
+        let bytes: String = self.body.iter().map(|&b| b as char).collect();
+        ret.set(&context, &v8::value::String::from_str(&isolate, "bytes"),
+            &v8::value::String::from_str(&isolate, &bytes));
+
+        ret
+    }
+}
+
+/// Requests started by `fetch()` that have not been sent yet.
+#[derive(Default)]
+pub struct Queue {
+    next_id: i32,
+    requests: Vec<(i32, Request, Option<Vec<u8>>)>,
+}
+
+impl Queue {
+    /// Queues `request`, returning the id its outcome is reported with.
+    pub fn push(&mut self, request: Request, body: Option<Vec<u8>>) -> i32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.requests.push((id, request, body));
+        id
+    }
+}
+
+/// `timeout`, shortened to what is left until `deadline`.
+fn until(deadline: Instant, timeout: Duration) -> Duration {
+    let now = Instant::now();
+    if deadline > now {
+        timeout.min(deadline - now)
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Checks `request` against the invocation's egress policy, logging what is
+/// denied.
+fn allow_egress(egress: &Arc<Mutex<Egress>>, request: &Request) -> Result<(), String> {
+    lock(egress)
+        .check(request.uri())
+        .map_err(|e| {
+            warn!("Egress denied for {}: {}", request.uri(), e);
+            format!("Egress denied: {}", e)
+        })
+}
+
+/// Reads the whole of `res`.
+fn read(res: hyper::Response) -> Box<Future<Item=Fetched, Error=String>> {
+    let status = res.status().as_u16();
+    let status_text = res.status().canonical_reason().unwrap_or("").to_string();
+    let headers = res.headers()
+        .iter()
+        .map(|h| Header::new(h.name().to_string(), h.value_string()))
+        .collect();
+    Box::new(res.body()
+        .concat2()
+        .map(move |body| Fetched {
+            status: status,
+            status_text: status_text,
+            headers: headers,
+            body: body.to_vec(),
+        })
+        .map_err(|e| format!("Failed to read response body: {}", e)))
+}
+
+/// Sends `request` and reads the response within `timeout`. Redirects are
+/// followed here rather than by the client when `follow` is set, so every
+/// hop passes the egress policy; its addresses are checked by the connector.
+fn exchange(client: &Client<Connector>, handle: &Handle, http: &HttpClients, egress: Arc<Mutex<Egress>>,
+            request: Request, body: Option<Vec<u8>>, timeout: Duration, follow: bool) -> Box<Future<Item=Fetched, Error=String>> {
+    let client = client.clone();
+    let user_agent = http.config().user_agent.clone();
+    let proxied = http.proxied();
+    let hops = future::loop_fn((request, body, 0), move |(request, body, hops)| {
+        let sent = allow_egress(&egress, &request).and_then(|_| request.to_hyper(body.clone()));
+        let mut sent =
+            match sent {
+                Ok(s) => s,
+                Err(e) => return Either::A(future::err(e)),
+            };
+        if !sent.headers().has::<UserAgent>() {
+            sent.headers_mut().set(UserAgent::new(user_agent.clone()));
+        }
+        sent.set_proxy(proxied && request.uri().scheme() == "http");
+
+        Either::B(client.request(sent)
+            .map_err(|e| match e {
+                hyper::Error::Io(ref e) if e.kind() == io::ErrorKind::PermissionDenied => e.to_string(),
+                e => format!("Request failed: {}", e),
+            })
+            .and_then(move |res| -> Box<Future<Item=Loop<Fetched, (Request, Option<Vec<u8>>, usize)>, Error=String>> {
+                let location =
+                    match res.headers().get::<Location>() {
+                        Some(l) if follow && res.status().is_redirection() => request.uri().join(l).ok(),
+                        _ => None,
+                    };
+                let location =
+                    match location {
+                        Some(l) => l,
+                        None => return Box::new(read(res).map(Loop::Break)),
+                    };
+                if hops >= outbound::MAX_REDIRECTS {
+                    return Box::new(future::err("Request failed: too many redirects".to_string()));
+                }
+                let (next, keep_body) = request.redirected(res.status().as_u16(), location);
+                Box::new(future::ok(Loop::Continue((next, if keep_body { body } else { None }, hops + 1))))
+            }))
+    });
+
+    let timer =
+        match Timeout::new(timeout, handle) {
+            Ok(t) => t,
+            Err(e) => return Box::new(future::err(format!("Unable to start timer: {}", e))),
+        };
+    Box::new(hops.select2(timer).then(|res| match res {
+        Ok(Either::A((fetched, _))) => Ok(fetched),
+        Ok(Either::B(_)) => Err("Request failed: timed out".to_string()),
+        Err(Either::A((e, _))) => Err(e),
+        Err(Either::B((e, _))) => Err(format!("Unable to start timer: {}", e)),
+    }))
+}
+
+/// The loop and the client whose connections it drives, shared by `fetch()`
+/// and `http.request`.
+struct Shared {
+    core: Core,
+    connector: Connector,
+    client: Client<Connector>,
+}
+
+/// Drives the outbound requests of the lambdas run by one executor thread,
+/// so several of them can be in flight while a handler awaits them.
+pub struct EventLoop {
+    shared: Rc<RefCell<Shared>>,
+    http: Arc<HttpClients>,
+    /// The policy of the invocation running now.
+    egress: Arc<Mutex<Egress>>,
+    queue: Arc<Mutex<Queue>>,
+    done: mpsc::Receiver<(i32, Result<Fetched, String>)>,
+    notify: mpsc::Sender<(i32, Result<Fetched, String>)>,
+    /// Requests in flight by id, dropping a sender cancels its request.
+    running: HashMap<i32, oneshot::Sender<()>>,
+    /// When the invocation running now runs out of time, no request it
+    /// sends is waited on longer.
+    deadline: Instant,
+}
+
+impl EventLoop {
+    pub fn new(http: Arc<HttpClients>) -> Result<EventLoop, String> {
+        let core = Core::new().map_err(|e| format!("Unable to start the event loop: {}", e))?;
+        let egress = Arc::new(Mutex::new(Egress::default()));
+        let connector = http.connector(&core.handle(), egress.clone())?;
+        let client = http.client(connector.clone(), &core.handle());
+        let (notify, done) = mpsc::channel();
+        Ok(EventLoop {
+            shared: Rc::new(RefCell::new(Shared { core: core, connector: connector, client: client })),
+            http: http,
+            egress: egress,
+            queue: Arc::new(Mutex::new(Queue::default())),
+            done: done,
+            notify: notify,
+            running: HashMap::new(),
+            deadline: Instant::now(),
+        })
+    }
+
+    /// Sends the requests of `http.request` on this loop.
+    pub fn blocking(&self) -> Blocking {
+        Blocking { shared: self.shared.clone(), http: self.http.clone(), egress: self.egress.clone() }
+    }
+
+    /// When the invocation running now runs out of time.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Where `fetch()` queues its requests.
+    pub fn queue(&self) -> Arc<Mutex<Queue>> {
+        self.queue.clone()
+    }
+
+    /// Cancels whatever an earlier invocation left behind and applies the
+    /// next invocation's egress policy and `deadline`. Connections opened
+    /// under another policy are not reused.
+    pub fn reset(&mut self, egress: Egress, deadline: Instant) {
+        if *lock(&self.egress) != egress {
+            let mut shared = self.shared.borrow_mut();
+            let shared = &mut *shared;
+            shared.client = self.http.client(shared.connector.clone(), &shared.core.handle());
+        }
+        *lock(&self.egress) = egress;
+        self.deadline = deadline;
+        self.running.clear();
+        lock(&self.queue).requests.clear();
+        while self.done.try_recv().is_ok() {}
+    }
+
+    /// Sends the queued requests and runs the loop for up to `timeout`,
+    /// returning the requests that finished by then.
+    pub fn turn(&mut self, timeout: Duration) -> Vec<(i32, Result<Fetched, String>)> {
+        let requests = mem::replace(&mut lock(&self.queue).requests, Vec::new());
+        for (id, request, body) in requests {
+            self.send(id, request, body);
+        }
+
+        self.shared.borrow_mut().core.turn(Some(timeout));
+        let done: Vec<_> = self.done.try_iter().collect();
+        for &(id, _) in done.iter() {
+            self.running.remove(&id);
+        }
+        done
+    }
+
+    fn send(&mut self, id: i32, request: Request, body: Option<Vec<u8>>) {
+        let shared = self.shared.borrow();
+        let handle = shared.core.handle();
+        let notify = self.notify.clone();
+        let fetch = exchange(&shared.client, &handle, &self.http, self.egress.clone(),
+                             request, body, until(self.deadline, self.http.config().timeout), true)
+            .then(move |result| {
+                let _ = notify.send((id, result));
+                Ok::<(), ()>(())
+            });
+
+        let (cancel, canceled) = oneshot::channel();
+        handle.spawn(fetch.select2(canceled).then(|_| Ok(())));
+        self.running.insert(id, cancel);
+    }
+}
+
+/// Sends `http.request` calls on an executor's event loop, blocking until
+/// the response arrived. Requests started by `fetch()` make progress in the
+/// meantime.
+pub struct Blocking {
+    shared: Rc<RefCell<Shared>>,
+    http: Arc<HttpClients>,
+    egress: Arc<Mutex<Egress>>,
+}
+
+impl Blocking {
+    /// `timeout` defaults to `HTTP_TIMEOUT_MS`, neither waits past the
+    /// invocation's `deadline`.
+    pub fn send(&self, request: Request, body: Option<Vec<u8>>, timeout: Option<Duration>, follow: bool, deadline: Instant) -> Result<Fetched, String> {
+        let mut shared =
+            match self.shared.try_borrow_mut() {
+                Ok(s) => s,
+                Err(_) => return Err("Request failed: the event loop is busy".to_string()),
+            };
+        let shared = &mut *shared;
+        let timeout = until(deadline, timeout.unwrap_or(self.http.config().timeout));
+        let exchange = exchange(&shared.client, &shared.core.handle(), &self.http, self.egress.clone(),
+                                request, body, timeout, follow);
+        shared.core.run(exchange)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_end_at_the_deadline() {
+        let timeout = Duration::from_secs(30);
+        let left = until(Instant::now() + Duration::from_secs(2), timeout);
+        assert!(left <= Duration::from_secs(2) && left > Duration::from_secs(1));
+        assert_eq!(until(Instant::now() + Duration::from_secs(60), timeout), timeout);
+    }
+
+    #[test]
+    fn nothing_is_left_past_the_deadline() {
+        let deadline = Instant::now();
+        assert_eq!(until(deadline, Duration::from_secs(30)), Duration::from_secs(0));
+    }
+}
@@ -1,25 +1,37 @@
 extern crate v8;
-extern crate reqwest;
 extern crate serde;
 extern crate serde_json;
 
-use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use fetch;
 use response::{Response};
-use request::{Request};
+use request::{self, Request};
 use headers::{Header};
 
+/// Turns a message into the value thrown back into JavaScript.
+fn throw(isolate: &v8::isolate::Isolate, message: &str) -> v8::value::Value {
+    v8::value::Value::from(v8::value::String::from_str(isolate, message))
+}
+
 pub fn parse_json(info: v8::value::FunctionCallbackInfo) -> Result<v8::value::Value, v8::value::Value> {
     let context = v8::Context::new(&info.isolate);
     let request_obj = info.this.clone();
     match request_obj.get(&context, &v8::value::String::from_str(&info.isolate, "body")).into_string() {
         Some(r) => {
             let global = context.global();
-            let json = global.get(&context, &v8::value::String::from_str(&info.isolate, "JSON")).into_object().unwrap();
-            let parse = json.get(&context, &v8::value::String::from_str(&info.isolate, "parse")).into_function().unwrap();
+            let parse =
+                global.get(&context, &v8::value::String::from_str(&info.isolate, "JSON")).into_object()
+                    .and_then(|json| json.get(&context, &v8::value::String::from_str(&info.isolate, "parse")).into_function());
+            let parse =
+                match parse {
+                    Some(p) => p,
+                    None => return Err(throw(&info.isolate, "JSON.parse is not available")),
+                };
             let req_json = v8::value::String::from_str(&info.isolate, r.value().as_str());
             match parse.call(&context, &[&req_json]) {
-                Ok(resp) => Ok(v8::value::Value::from(resp.into_object().unwrap())),
+                Ok(resp) => Ok(resp),
                 _ => {
                     let err = v8::value::String::from_str(&info.isolate, "Invalid json");
                     Err(v8::value::Value::from(err))
@@ -33,60 +45,45 @@ pub fn parse_json(info: v8::value::FunctionCallbackInfo) -> Result<v8::value::Va
     }
 }
 
-pub fn make_request(info: v8::value::FunctionCallbackInfo) -> Result<v8::value::Value, v8::value::Value> {
-    match info.args.as_slice() {
-        [key] => {
-            match key.clone().into_object() {
-                Some(k) => {
-                    let context = v8::Context::new(&info.isolate);
-                    let request = Request::from_js(&info.isolate, &context, &k).unwrap();
-
-                    let client = reqwest::Client::new();
-                    match request.to_reqwest(&client) {
-                        Ok(req) => {
-                            let mut resp = client.execute(req);
-
-                            match resp.as_mut() {
-                                Ok(r) => {
-                                    // response body
-                                    let mut buf: Vec<u8> = vec![];
-                                    r.copy_to(&mut buf).unwrap();
-                                    let body = str::from_utf8(&buf).unwrap();
-
-                                    // response
-                                    let status = r.status().as_u16();
-                                    let response = Response { status: status as i32, headers: Vec::new(), body: body.to_string() };
-                                    let response_js = response.js(&info.isolate, &context);
-
-                                    // add .json() helper to response object
-                                    let parse_json = v8::value::Function::new(&info.isolate, &context, 0, Box::new(parse_json));
-                                    response_js.set(&context, &v8::value::String::from_str(&info.isolate, "json"), &parse_json);
-
-                                    Ok(v8::value::Value::from(response_js))
-                                },
-                                Err(_) => {
-                                    let err = v8::value::String::from_str(&info.isolate, "Invalid Request Method!");
-                                    Err(v8::value::Value::from(err)) 
-                                }
-                            }
-                        },
-                        Err(_) => {
-                            let err = v8::value::String::from_str(&info.isolate, "Invalid Request!");
-                            Err(v8::value::Value::from(err))                             
-                        }
-                    }
-                },
-                None => {
-                    let err = v8::value::String::from_str(&info.isolate, "Invalid Request!");
-                    Err(v8::value::Value::from(err)) 
-                }
-            }
-        },
-        _ => {
-            let err = v8::value::String::from_str(&info.isolate, "Invalid Request!");
-            Err(v8::value::Value::from(err)) 
-        }
-    }
+/// `__http(request)`, sends `request` on the executor's event loop and waits
+/// for the whole response, at most until the invocation's `deadline`.
+/// `timeout` (milliseconds) and `followRedirects` apply to this call only.
+pub fn make_request(blocking: &fetch::Blocking, deadline: Instant, info: v8::value::FunctionCallbackInfo) -> Result<v8::value::Value, v8::value::Value> {
+    let context = v8::Context::new(&info.isolate);
+    let js =
+        match info.args.first() {
+            Some(r) => r.clone(),
+            None => return Err(throw(&info.isolate, "Invalid Request!")),
+        };
+    let request = Request::from_js(&info.isolate, &context, &js).map_err(|e| throw(&info.isolate, &e))?;
+    let body = request::body_from_js(&info.isolate, &context, &js);
+
+    let options =
+        match js.into_object() {
+            Some(o) => o,
+            None => return Err(throw(&info.isolate, "Invalid Request!")),
+        };
+    let timeout = options.get(&context, &v8::value::String::from_str(&info.isolate, "timeout")).into_int32()
+        .map(|ms| Duration::from_millis(ms.value().max(0) as u64));
+    let follow = options.get(&context, &v8::value::String::from_str(&info.isolate, "followRedirects")).is_true();
+
+    let fetched = blocking.send(request, body, timeout, follow, deadline).map_err(|e| throw(&info.isolate, &e))?;
+    Ok(v8::value::Value::from(fetched.js(&info.isolate, &context)))
+}
+
+/// `__fetch(request)`, queues `request` on the executor's event loop and
+/// returns the id `__fetchDone` reports its outcome with.
+pub fn queue_fetch(queue: &Arc<Mutex<fetch::Queue>>, info: v8::value::FunctionCallbackInfo) -> Result<v8::value::Value, v8::value::Value> {
+    let context = v8::Context::new(&info.isolate);
+    let request =
+        match info.args.first() {
+            Some(r) => Request::from_js(&info.isolate, &context, r).map_err(|e| throw(&info.isolate, &e))?,
+            None => return Err(throw(&info.isolate, "Invalid Request!")),
+        };
+    let body = info.args.first().and_then(|r| request::body_from_js(&info.isolate, &context, r));
+
+    let id = queue.lock().unwrap_or_else(|e| e.into_inner()).push(request, body);
+    Ok(v8::value::Value::from(v8::value::Integer::new(&info.isolate, id)))
 }
 
 pub fn get_header(info: v8::value::FunctionCallbackInfo) -> Result<v8::value::Value, v8::value::Value> {
@@ -95,7 +92,11 @@ pub fn get_header(info: v8::value::FunctionCallbackInfo) -> Result<v8::value::Va
             match key.clone().into_string() {
                 Some(k) => {
                     let context = v8::Context::new(&info.isolate);
-                    let request_obj = Request::from_js(&info.isolate, &context, &info.this.clone()).unwrap();
+                    let request_obj =
+                        match Request::from_js(&info.isolate, &context, &info.this.clone()) {
+                            Ok(r) => r,
+                            Err(e) => return Err(throw(&info.isolate, &e)),
+                        };
 
                     match request_obj.headers().iter().find(|&&ref x| x.name() == k.value()) {
                         Some(ret) => {
@@ -131,8 +132,16 @@ pub fn add_header(info: v8::value::FunctionCallbackInfo) -> Result<v8::value::Va
                     let new_header = Header::new(k.value(), v.value()).js(&info.isolate, &context);
 
                     let response_obj = info.this.clone();
-                    let request_obj = Response::from_js(&info.isolate, &context, &response_obj);
-                    let list = response_obj.get(&context, &v8::value::String::from_str(&info.isolate, "headers")).into_array().unwrap();
+                    let request_obj =
+                        match Response::from_js(&info.isolate, &context, &response_obj) {
+                            Ok(r) => r,
+                            Err(e) => return Err(throw(&info.isolate, &e)),
+                        };
+                    let list =
+                        match response_obj.get(&context, &v8::value::String::from_str(&info.isolate, "headers")).into_array() {
+                            Some(l) => l,
+                            None => return Err(throw(&info.isolate, "Invalid Response: headers must be an array")),
+                        };
 
                     let index = request_obj.headers.len() as i32;
                     list.set(&context, &v8::value::Integer::new(&info.isolate, index), &new_header);
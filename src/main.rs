@@ -4,24 +4,28 @@ extern crate serde_json;
 extern crate serde_derive;
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
+extern crate failure_derive;
+extern crate failure;
+#[macro_use]
+extern crate log;
 extern crate actix;
 extern crate actix_web;
 extern crate env_logger;
 extern crate futures;
 extern crate r2d2;
 extern crate v8;
-extern crate reqwest;
 
 use actix::prelude::{SyncArbiter};
-use actix_web::{http, server, Path, App, AsyncResponder, FutureResponse,
-                HttpResponse, HttpRequest};
+use actix_web::{http, server, Path, Query, Json, App, AsyncResponder, FutureResponse,
+                HttpResponse, HttpRequest, ResponseError};
 use http::{StatusCode};
 use actix_web::middleware::Logger;
-use diesel::pg::PgConnection;
-use diesel::r2d2::ConnectionManager;
-use futures::Future;
-use std::env;
-use traits::{ToString};
+use futures::{future, Future};
+use std::process;
+use std::sync::Arc;
 
 mod models;
 mod db;
@@ -31,142 +35,749 @@ mod request;
 mod response;
 mod traits;
 mod functions;
+mod traffic;
+mod router;
+mod storage;
+mod migrate;
+mod config;
+mod auth;
+mod runtime;
+mod native;
+mod error;
+mod executor;
+mod metrics;
+mod cache;
+mod concurrency;
+mod ratelimit;
+mod fetch;
+mod outbound;
+mod egress;
 
 use request::{Request};
-use headers::{Header};
 use response::{Response};
-use db::{GetLambda, CreateLambda, DbExecutor, AppState};
+use error::Error;
+use executor::{Execute, JsExecutor, Queue, Validate};
+use concurrency::Limiter;
+use metrics::Metrics;
+use ratelimit::RateLimiter;
+use cache::LambdaCache;
+use outbound::HttpClients;
+use config::{Config, Command};
+use db::{GetLambda, FindLambda, CreateLambda, ListLambdas, DeleteLambda, ListVersions, RollbackLambda,
+         ListAliases, SetAlias, DeleteAlias, GetTraffic, SetTraffic, GetLimits, SetLimits,
+         GetHostLimits, SetHostLimits, GetEgress, SetEgress, GetHostEgress, SetHostEgress,
+         Dispatch, ListApiKeys, CreateApiKey,
+         DeleteApiKey, DbExecutor, AppState};
+
+/// Unwraps a `Result<_, Error>` inside a handler, answering with the error
+/// response when it fails.
+macro_rules! try_response {
+    ($e:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(e) => return Box::new(future::ok(e.error_response())),
+        }
+    }
+}
+
+/// Selects a named alias (e.g. `prod`, `staging`) instead of the active version.
+const STAGE_HEADER: &str = "X-Lambda-Stage";
+/// Reports which version of the lambda served the request.
+const VERSION_HEADER: &str = "X-Lambda-Version";
+
+/// The `methods` query parameter selects which method-bound registration of a
+/// path the admin API operates on, e.g. `?methods=GET,HEAD`. Omitting it means
+/// the registration that accepts any method.
+fn lambda_methods(req: &HttpRequest<AppState>) -> Option<String> {
+    req.query().get("methods").map(|m| m.to_string())
+}
 
 fn create_lambda(body: String, name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
-    let request = Request::map(&name, &req, None); 
+    let request = try_response!(Request::map(&name, &req, None)); 
+    // compiling runs the top level code, it waits for an executor like an
+    // invocation does
+    let slot = try_response!(req.state().queue.acquire());
+    req.state()
+        .js
+        .send(Validate {
+            code: body.clone(),
+            limits: req.state().limits,
+        })
+        .from_err()
+        .and_then(move |res| -> FutureResponse<HttpResponse> {
+            drop(slot);
+            let code_cache = try_response!(res);
+
+            req.clone()
+                .state()
+                .db
+                .send(CreateLambda {
+                    path: request.path(),
+                    hostname: request.host(),
+                    methods: lambda_methods(&req),
+                    code: body,
+                    code_cache: code_cache,
+                })
+                .from_err()
+                .and_then(move |res| match res {
+                    Ok(lambda) => Ok(HttpResponse::Ok().json(lambda)),
+                    Err(e) => Ok(e.error_response()),
+                })
+                .responder()
+        })
+        .responder()
+}
+
+fn list_lambdas(filter: Query<request::LambdaFilter>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let identity = req.extensions().get::<auth::Identity>().cloned();
+    req.state()
+        .db
+        .send(ListLambdas {
+            hostname: filter.into_inner().hostname,
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(lambdas) => {
+                // keys only see the hostnames they are scoped to
+                let visible: Vec<_> = lambdas.into_iter()
+                    .filter(|l| identity.as_ref().map(|i| i.allows(&l.hostname)).unwrap_or(false))
+                    .collect();
+                Ok(HttpResponse::Ok().json(visible))
+            },
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn get_lambda(name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
     req.clone()
         .state()
         .db
-        .send(CreateLambda {
+        .send(FindLambda {
             path: request.path(),
             hostname: request.host(),
-            code: body,
+            methods: lambda_methods(&req),
         })
         .from_err()
-        .and_then(move |res| match res {
+        .and_then(|res| match res {
             Ok(lambda) => Ok(HttpResponse::Ok().json(lambda)),
-            Err(_) => Ok(HttpResponse::InternalServerError().into()),
+            Err(e) => Ok(e.error_response()),
         })
         .responder()
 }
 
-fn make_response<T: ToString>(status: u16, headers: &Vec<Header>, body: T) -> HttpResponse {
-    let mut resp = HttpResponse::build(StatusCode::from_u16(status).unwrap());
-    for h in headers.iter() {
-        resp.header(h.name().as_str(), h.value().as_str());
-    }
-    resp.body(body.get_string())
+fn delete_lambda(name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(DeleteLambda {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(lambda) => Ok(HttpResponse::Ok().json(lambda)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
 }
 
-fn exec_lambda(body: String, name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+fn list_versions(name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(ListVersions {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(versions) => Ok(HttpResponse::Ok().json(versions)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn rollback_lambda(params: Json<request::VersionParams>, name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(RollbackLambda {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+            version: params.version,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(lambda) => Ok(HttpResponse::Ok().json(lambda)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn list_aliases(name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(ListAliases {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(aliases) => Ok(HttpResponse::Ok().json(aliases)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn set_alias(params: Json<request::VersionParams>, name: Path<request::AliasPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name.lambda_path(), &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(SetAlias {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+            name: name.alias.clone(),
+            version: params.version,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(alias) => Ok(HttpResponse::Ok().json(alias)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn delete_alias(name: Path<request::AliasPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name.lambda_path(), &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(DeleteAlias {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+            name: name.alias.clone(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(alias) => Ok(HttpResponse::Ok().json(alias)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn get_traffic(name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(GetTraffic {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(config) => Ok(HttpResponse::Ok().json(config)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn set_traffic(config: Json<request::TrafficConfig>, name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(SetTraffic {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+            config: config.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(config) => Ok(HttpResponse::Ok().json(config)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn get_limits(name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(GetLimits {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(limits) => Ok(HttpResponse::Ok().json(limits)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn set_limits(limits: Json<models::LambdaLimits>, name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(SetLimits {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+            limits: limits.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(limits) => Ok(HttpResponse::Ok().json(limits)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn get_host_limits(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&request::LambdaPath::default(), &req, None));
+    req.state()
+        .db
+        .send(GetHostLimits {
+            hostname: request.host(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(limits) => Ok(HttpResponse::Ok().json(limits)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn set_host_limits(limits: Json<models::HostLimits>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&request::LambdaPath::default(), &req, None));
+    let mut limits = limits.into_inner();
+    limits.hostname = request.host();
+    req.state()
+        .db
+        .send(SetHostLimits {
+            limits: limits,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(limits) => Ok(HttpResponse::Ok().json(limits)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn get_egress(name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(GetEgress {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(policy) => Ok(HttpResponse::Ok().json(policy)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn set_egress(policy: Json<egress::EgressPolicy>, name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
     req.clone()
         .state()
         .db
-        .send(GetLambda {
-            request: Request::map(&name, &req, None),
+        .send(SetEgress {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+            policy: Some(policy.into_inner()),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(policy) => Ok(HttpResponse::Ok().json(policy)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn delete_egress(name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&name, &req, None));
+    req.clone()
+        .state()
+        .db
+        .send(SetEgress {
+            path: request.path(),
+            hostname: request.host(),
+            methods: lambda_methods(&req),
+            policy: None,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(policy) => Ok(HttpResponse::Ok().json(policy)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn get_host_egress(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&request::LambdaPath::default(), &req, None));
+    req.state()
+        .db
+        .send(GetHostEgress {
+            hostname: request.host(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(policy) => Ok(HttpResponse::Ok().json(policy)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn set_host_egress(policy: Json<egress::EgressPolicy>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&request::LambdaPath::default(), &req, None));
+    req.state()
+        .db
+        .send(SetHostEgress {
+            hostname: request.host(),
+            policy: Some(policy.into_inner()),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(policy) => Ok(HttpResponse::Ok().json(policy)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn delete_host_egress(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let request = try_response!(Request::map(&request::LambdaPath::default(), &req, None));
+    req.state()
+        .db
+        .send(SetHostEgress {
+            hostname: request.host(),
+            policy: None,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(policy) => Ok(HttpResponse::Ok().json(policy)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn get_metrics(req: HttpRequest<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(req.state().metrics.snapshot())
+}
+
+fn list_keys(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    req.state()
+        .db
+        .send(ListApiKeys)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(keys) => Ok(HttpResponse::Ok().json(keys)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn create_key(params: Json<auth::KeyParams>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let hostnames =
+        match auth::normalize_hostnames(&params.hostnames) {
+            Ok(h) => h,
+            Err(e) => return Box::new(future::ok(Error::BadRequest(e).error_response())),
+        };
+    let key = auth::generate_key();
+    req.state()
+        .db
+        .send(CreateApiKey {
+            name: params.name.clone(),
+            key_hash: auth::hash_key(&key),
+            hostnames: hostnames,
         })
         .from_err()
         .and_then(move |res| match res {
-            Ok(lambda) => {
-                let isolate = v8::Isolate::new();
-                let context = v8::Context::new(&isolate);
-
-                let request = Request::map(&name, &req, Some(body));
-
-                let js_request = request.js(&isolate, &context);
-                // initial response object
-                let js_response = Response::new().js(&isolate, &context);
-
-                // Load the source code that we want to evaluate
-                let source = v8::value::String::from_str(&isolate, &lambda.code);
-
-                // Compile the source code.  `unwrap()` panics if the code is invalid,
-                // e.g. if there is a syntax  error.
-                let script = v8::Script::compile(&isolate, &context, &source).unwrap();
-                match script.run(&context) {
-                    Ok(_) => {
-                        let global = context.global();
-                        // helper functions
-                        let http = v8::value::Object::new(&isolate, &context);
-                        let http_request = v8::value::Function::new(&isolate, &context, 1, Box::new(functions::make_request));
-                        http.set(&context, &v8::value::String::from_str(&isolate, "request"), &http_request);
-                        global.set(&context, &v8::value::String::from_str(&isolate, "http"), &http);
-
-                        // set default response values
-                        global.set(&context, &v8::value::String::from_str(&isolate, "response"), &js_response);
-
-                        // endpoint    
-                        let value = global.get(&context, &v8::value::String::from_str(&isolate, "handler"));
-                        let fun = value.into_function().unwrap();
-                        match fun.call(&context, &[&js_request]) {
-                            Ok(res) => {
-                                // Convert the result to a value::String.
-                                let result_str = res.to_string(&context);
-                                let response_val = global.get(&context, &v8::value::String::from_str(&isolate, "response"));
-                                let response_obj = Response::from_js(&isolate, &context, &response_val.into_object().unwrap());
-
-                                let mut resp = HttpResponse::build(StatusCode::from_u16(response_obj.status as u16).unwrap());
-                                for val in response_obj.headers {
-                                    resp.header(val.name().as_str(), val.value().as_str());
-                                }
-
-                                // doc says to run this "frequently" ??
-                                isolate.run_enqueued_tasks();
-
-                                Ok(resp.body(result_str.value()))
-                            },
-                            Err(e) => {
-                                println!("ERR! {:?}", e);
-                                Ok(HttpResponse::InternalServerError().body("Internal Error"))
-                            },
-                        }
-                    },
-                    Err(_) => {
-                        Ok(HttpResponse::InternalServerError().body("Internal Error"))
-                    }
-                }
+            Ok(created) => Ok(HttpResponse::Ok().json(auth::IssuedKey {
+                id: created.id,
+                name: created.name,
+                hostnames: created.hostnames,
+                key: key,
+            })),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+fn delete_key(id: Path<auth::KeyPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    req.state()
+        .db
+        .send(DeleteApiKey {
+            id: id.id,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(key) => Ok(HttpResponse::Ok().json(key)),
+            Err(e) => Ok(e.error_response()),
+        })
+        .responder()
+}
+
+/// Response headers set by the server itself.
+const FRAMING_HEADERS: &[&str] = &["content-length", "transfer-encoding", "content-encoding", "connection"];
+
+fn make_response(response: Response, version: i32) -> Result<HttpResponse, Error> {
+    let status =
+        if response.status >= 100 && response.status < 600 {
+            StatusCode::from_u16(response.status as u16).ok()
+        } else {
+            None
+        };
+    let status = status
+        .ok_or_else(|| Error::Bridge(format!("Invalid Response: status {} is out of range", response.status)))?;
+
+    let mut resp = HttpResponse::build(status);
+    for h in response.headers.iter() {
+        // the body is framed here, upstream headers copied from http.request
+        // describe a different one
+        if FRAMING_HEADERS.iter().any(|f| h.name().eq_ignore_ascii_case(f)) {
+            continue;
+        }
+        let valid = http::header::HeaderName::from_bytes(h.name().as_bytes()).is_ok()
+            && http::header::HeaderValue::from_str(h.value().as_str()).is_ok();
+        if !valid {
+            return Err(Error::Bridge(format!("Invalid Response: invalid header {:?}", h.name())));
+        }
+        resp.header(h.name().as_str(), h.value().as_str());
+    }
+    resp.header(VERSION_HEADER, version.to_string().as_str());
+    Ok(resp.body(response.body))
+}
+
+fn exec_lambda(body: String, name: Path<request::LambdaPath>, req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    // the rate limits already had to resolve the route
+    let dispatch: Box<Future<Item=Result<Dispatch, Error>, Error=actix_web::Error>> =
+        match req.clone().extensions_mut().remove::<Dispatch>() {
+            Some(dispatch) => Box::new(future::ok(Ok(dispatch))),
+            None => {
+                let request = try_response!(Request::map(&name, &req, None));
+                let stage = request.header(STAGE_HEADER);
+                Box::new(req.state()
+                    .db
+                    .send(GetLambda {
+                        request: request,
+                        stage: stage,
+                    })
+                    .from_err())
             },
-            Err(_) => {
-                Ok(make_response(404, &Vec::new(), "Not Found"))
-            }
+        };
+    dispatch
+        .and_then(move |res| -> FutureResponse<HttpResponse> {
+            let dispatch = try_response!(res);
+            let egress = try_response!(dispatch.egress());
+            let Dispatch { route, host_limits, .. } = dispatch;
+
+            let permits = Limiter::acquire_route(&req.state().limiter, &host_limits, &route.lambda);
+
+            Box::new(permits.then(move |permits| -> FutureResponse<HttpResponse> {
+                let permits = try_response!(permits);
+                let slot = try_response!(req.state().queue.acquire());
+                let request = try_response!(Request::map(&name, &req, Some(body))).with_params(route.params);
+                let limits = req.state().limits.for_lambda(&route.lambda);
+                let version = route.lambda.version;
+
+                req.state()
+                    .js
+                    .send(Execute {
+                        lambda_id: route.lambda.id,
+                        version: version,
+                        code: route.lambda.code,
+                        code_cache: runtime::CodeCache::stored(route.lambda.code_cache, route.lambda.code_cache_tag),
+                        request: request,
+                        limits: limits,
+                        egress: egress,
+                    })
+                    .from_err()
+                    .and_then(move |res| {
+                        // the executor is done with it, free the slots for the next one
+                        drop(slot);
+                        drop(permits);
+                        match res.and_then(|response| make_response(response, version)) {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => Ok(e.error_response()),
+                        }
+                    })
+                    .responder()
+            }))
         })
         .responder()
 }
 
 fn main() {
-    std::env::set_var("RUST_LOG", "actix_web=info");
+    std::env::set_var("RUST_LOG", "actix_web=info,serverless=info");
     env_logger::init();
-    let sys = actix::System::new("serverless");
 
-    let database_url =
-        match env::var("DATABASE_URL") {
-            Ok(v) => v,
-            Err(_) => "postgres://postgres:@localhost/serverless".to_string(),
+    let config =
+        match Config::from_env() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(2);
+            },
         };
-    let listen_addr =
-        match env::var("LISTEN_ADDR") {
-            Ok(v) => v,
-            Err(_) => "127.0.0.1:8088".to_string(),
+
+    let storage =
+        match storage::connect(&config.database_url) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            },
+        };
+
+    let migrated =
+        match config.command {
+            Command::Migrate => {
+                if let Err(e) = migrate::run(&*storage) {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+                return;
+            },
+            Command::Serve if config.auto_migrate => migrate::run(&*storage),
+            Command::Serve => migrate::check(&*storage),
         };
+    if let Err(e) = migrated {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 
-    let manager = ConnectionManager::<PgConnection>::new(database_url);
-    let pool = r2d2::Pool::builder()
-        .build(manager)
-        .expect("Failed to create pool.");
+    let sys = actix::System::new("serverless");
+
+    let cache = Arc::new(LambdaCache::new(config.cache_ttl));
+    if let Err(e) = cache::watch(&config.database_url, cache.clone()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 
-    let addr = SyncArbiter::start(3, move || DbExecutor(pool.clone()));
+    let addr = SyncArbiter::start(3, move || DbExecutor(storage.clone(), cache.clone()));
+    let watchdog =
+        match runtime::Watchdog::start() {
+            Ok(w) => w,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(2);
+            },
+        };
+    let metrics = Arc::new(Metrics::default());
+    let warm_isolates = config.warm_isolates;
+    let pool_metrics = metrics.clone();
+    let http =
+        match HttpClients::new(config.http.clone()) {
+            Ok(h) => Arc::new(h),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(2);
+            },
+        };
+    // the pool is built on the executor's thread as it starts, there is no
+    // caller to hand an error to
+    let js = SyncArbiter::start(config.js_workers, move || {
+        match runtime::IsolatePool::new(warm_isolates, pool_metrics.clone(), http.clone(), watchdog.clone()) {
+            Ok(pool) => JsExecutor(pool),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            },
+        }
+    });
+    let queue = Queue::new(config.js_workers + config.js_queue);
+    let limiter = Arc::new(Limiter::new(config.queue_size, config.queue_wait, metrics.clone()));
+    let rate_limiter = Arc::new(RateLimiter::new(metrics.clone()));
+    let admin_key = config.admin_key.clone();
+    let limits = config.limits;
 
     server::new(move
-        || App::with_state(AppState{db: addr.clone()})
+        || App::with_state(AppState{
+                db: addr.clone(),
+                js: js.clone(),
+                queue: queue.clone(),
+                metrics: metrics.clone(),
+                limiter: limiter.clone(),
+                rate_limiter: rate_limiter.clone(),
+                admin_key: admin_key.clone(),
+                limits: limits,
+            })
             .middleware(Logger::default())
-            .resource("/v1/lambda/{path}", |r| r.method(http::Method::POST).with3(create_lambda))
-            .resource("/{path}", |r| r.route().with3(exec_lambda)))
-        .bind(listen_addr)
+            .middleware(auth::Authentication)
+            .middleware(ratelimit::RateLimit)
+            .resource("/v1/keys", |r| {
+                r.method(http::Method::GET).with(list_keys);
+                r.method(http::Method::POST).with2(create_key);
+            })
+            .resource("/v1/keys/{id}", |r| r.method(http::Method::DELETE).with2(delete_key))
+            .resource("/v1/host/limits", |r| {
+                r.method(http::Method::GET).with(get_host_limits);
+                r.method(http::Method::PUT).with2(set_host_limits);
+            })
+            .resource("/v1/host/egress", |r| {
+                r.method(http::Method::GET).with(get_host_egress);
+                r.method(http::Method::PUT).with2(set_host_egress);
+                r.method(http::Method::DELETE).with(delete_host_egress);
+            })
+            .resource("/v1/metrics", |r| r.method(http::Method::GET).with(get_metrics))
+            .resource("/v1/lambda", |r| r.method(http::Method::GET).with2(list_lambdas))
+            // lambda paths may span several segments, so the more specific
+            // management resources have to be registered first
+            .resource("/v1/lambda/{path:.*}/versions", |r| r.method(http::Method::GET).with2(list_versions))
+            .resource("/v1/lambda/{path:.*}/rollback", |r| r.method(http::Method::POST).with3(rollback_lambda))
+            .resource("/v1/lambda/{path:.*}/traffic", |r| {
+                r.method(http::Method::GET).with2(get_traffic);
+                r.method(http::Method::PUT).with3(set_traffic);
+            })
+            .resource("/v1/lambda/{path:.*}/limits", |r| {
+                r.method(http::Method::GET).with2(get_limits);
+                r.method(http::Method::PUT).with3(set_limits);
+            })
+            .resource("/v1/lambda/{path:.*}/egress", |r| {
+                r.method(http::Method::GET).with2(get_egress);
+                r.method(http::Method::PUT).with3(set_egress);
+                r.method(http::Method::DELETE).with2(delete_egress);
+            })
+            .resource("/v1/lambda/{path:.*}/aliases", |r| r.method(http::Method::GET).with2(list_aliases))
+            .resource("/v1/lambda/{path:.*}/aliases/{alias}", |r| {
+                r.method(http::Method::PUT).with3(set_alias);
+                r.method(http::Method::DELETE).with2(delete_alias);
+            })
+            .resource("/v1/lambda/{path:.*}", |r| {
+                r.method(http::Method::POST).with3(create_lambda);
+                r.method(http::Method::GET).with2(get_lambda);
+                r.method(http::Method::DELETE).with2(delete_lambda);
+            })
+            .resource("/{path:.*}", |r| r.route().with3(exec_lambda)))
+        .bind(config.listen_addr)
         .unwrap()
         .start();
 
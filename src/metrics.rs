@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counters shared by the executors and limits, reported by `GET /v1/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    /// Invocations that had to create an isolate and load the lambda's code.
+    pub cold_starts: AtomicUsize,
+    /// Invocations served by an isolate kept from an earlier invocation.
+    pub warm_starts: AtomicUsize,
+    /// Warm isolates dropped to make room for another lambda version.
+    pub evictions: AtomicUsize,
+    /// Warm isolates currently kept across all executors.
+    pub warm_isolates: AtomicUsize,
+    /// Requests that had to wait for a concurrency slot.
+    pub queued: AtomicUsize,
+    /// Requests answered with `429` by the concurrency limits.
+    pub throttled: AtomicUsize,
+    /// Requests answered with `429` by the rate limits.
+    pub rate_limited: AtomicUsize,
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    pub cold_starts: usize,
+    pub warm_starts: usize,
+    pub evictions: usize,
+    pub warm_isolates: usize,
+    pub queued: usize,
+    pub throttled: usize,
+    pub rate_limited: usize,
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cold_starts: self.cold_starts.load(Ordering::Relaxed),
+            warm_starts: self.warm_starts.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            warm_isolates: self.warm_isolates.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use storage::{Storage};
+
+/// Version of the newest migration embedded in this binary, shared by the
+/// postgres and sqlite migration directories. Bump it whenever a migration
+/// is added.
+pub const SCHEMA_VERSION: &str = "20180630100000";
+
+// `embed_migrations!` imports a trait it does not use
+#[allow(unused_imports)]
+pub mod postgres {
+    embed_migrations!("migrations/postgres");
+
+    pub use self::embedded_migrations::run;
+}
+
+#[allow(unused_imports)]
+pub mod sqlite {
+    embed_migrations!("migrations/sqlite");
+
+    pub use self::embedded_migrations::run;
+}
+
+/// Refuses to touch a database migrated by a newer build, whose schema this
+/// binary does not know how to read.
+pub fn check(storage: &Storage) -> Result<(), String> {
+    let applied = storage.schema_version()
+        .map_err(|e| format!("Unable to read the schema version: {}", e))?;
+
+    match applied {
+        Some(ref version) if version.as_str() > SCHEMA_VERSION => {
+            Err(format!("Database schema version {} is newer than the {} this binary supports, \
+                         upgrade serverless before starting it", version, SCHEMA_VERSION))
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Checks the schema version and applies any pending migration.
+pub fn run(storage: &Storage) -> Result<(), String> {
+    check(storage)?;
+    storage.migrate().map_err(|e| format!("Unable to run migrations: {}", e))
+}
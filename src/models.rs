@@ -1,11 +1,56 @@
-use super::schema::lambdas;
+use super::schema::{lambdas, lambda_versions, lambda_aliases, lambda_traffic, api_keys, host_limits, host_egress};
 
-#[derive(Serialize, Queryable)]
+#[derive(Clone, Serialize, Queryable)]
 pub struct Lambda {
     pub id: i32,
     pub path: String,
     pub hostname: String,
     pub code: String,
+    pub version: i32,
+    pub sticky: Option<String>,
+    pub methods: String,
+    pub timeout_ms: Option<i32>,
+    pub cpu_timeout_ms: Option<i32>,
+    pub heap_limit_mb: Option<i32>,
+    #[serde(skip_serializing)]
+    pub code_cache: Option<Vec<u8>>,
+    #[serde(skip_serializing)]
+    pub code_cache_tag: Option<String>,
+    pub max_concurrency: Option<i32>,
+    pub rate_limit: Option<i32>,
+    pub rate_limit_key: Option<String>,
+    /// `egress::EgressPolicy` as JSON, see `/egress`.
+    #[serde(skip_serializing)]
+    pub egress_policy: Option<String>,
+}
+
+impl Lambda {
+    pub fn limits(&self) -> LambdaLimits {
+        LambdaLimits {
+            timeout_ms: self.timeout_ms,
+            cpu_timeout_ms: self.cpu_timeout_ms,
+            heap_limit_mb: self.heap_limit_mb,
+            max_concurrency: self.max_concurrency,
+            rate_limit: self.rate_limit,
+            rate_limit_key: self.rate_limit_key.clone(),
+        }
+    }
+}
+
+/// Per-lambda execution limits, unset limits fall back to the server defaults.
+#[derive(Serialize, Deserialize, AsChangeset)]
+#[table_name = "lambdas"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct LambdaLimits {
+    pub timeout_ms: Option<i32>,
+    pub cpu_timeout_ms: Option<i32>,
+    pub heap_limit_mb: Option<i32>,
+    pub max_concurrency: Option<i32>,
+    /// Requests per minute and client.
+    pub rate_limit: Option<i32>,
+    /// What identifies a client: `ip` (the default), `header:<name>` or
+    /// `script` for the lambda's `rateLimitKey(request)`.
+    pub rate_limit_key: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -13,5 +58,106 @@ pub struct Lambda {
 pub struct NewLambda<'a> {
     pub path: &'a str,
     pub hostname: &'a str,
+    pub methods: &'a str,
+    pub code: &'a str,
+    pub code_cache: Option<&'a [u8]>,
+    pub code_cache_tag: Option<&'a str>,
+}
+
+#[derive(Clone, Serialize, Queryable)]
+pub struct LambdaVersion {
+    pub id: i32,
+    pub lambda_id: i32,
+    pub version: i32,
+    pub code: String,
+    pub created_at: i64,
+    #[serde(skip_serializing)]
+    pub code_cache: Option<Vec<u8>>,
+    #[serde(skip_serializing)]
+    pub code_cache_tag: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "lambda_versions"]
+pub struct NewLambdaVersion<'a> {
+    pub lambda_id: i32,
+    pub version: i32,
     pub code: &'a str,
+    pub created_at: i64,
+    pub code_cache: Option<&'a [u8]>,
+    pub code_cache_tag: Option<&'a str>,
+}
+
+#[derive(Clone, Serialize, Queryable)]
+pub struct LambdaAlias {
+    pub id: i32,
+    pub lambda_id: i32,
+    pub name: String,
+    pub version: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "lambda_aliases"]
+pub struct NewLambdaAlias<'a> {
+    pub lambda_id: i32,
+    pub name: &'a str,
+    pub version: i32,
+}
+
+#[derive(Clone, Serialize, Queryable)]
+pub struct LambdaTraffic {
+    pub id: i32,
+    pub lambda_id: i32,
+    pub version: i32,
+    pub weight: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "lambda_traffic"]
+pub struct NewLambdaTraffic {
+    pub lambda_id: i32,
+    pub version: i32,
+    pub weight: i32,
+}
+
+#[derive(Serialize, Queryable)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    pub hostnames: String,
+    pub created_at: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "api_keys"]
+pub struct NewApiKey<'a> {
+    pub name: &'a str,
+    pub key_hash: &'a str,
+    pub hostnames: &'a str,
+    pub created_at: i64,
+}
+
+/// Limits shared by every lambda of a hostname.
+#[derive(Clone, Default, Serialize, Deserialize, Queryable, Insertable, AsChangeset)]
+#[table_name = "host_limits"]
+#[primary_key(hostname)]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct HostLimits {
+    /// Taken from the request, not the body.
+    #[serde(default)]
+    pub hostname: String,
+    pub max_concurrency: Option<i32>,
+    pub rate_limit: Option<i32>,
+    pub rate_limit_key: Option<String>,
+}
+
+/// Egress policy shared by every lambda of a hostname.
+#[derive(Clone, Queryable, Insertable)]
+#[table_name = "host_egress"]
+pub struct HostEgress {
+    pub hostname: String,
+    /// `egress::EgressPolicy` as JSON.
+    pub policy: String,
 }
@@ -0,0 +1,228 @@
+// Parts of V8's C++ API the `v8` crate does not bind, see `native.rs`.
+
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include <v8.h>
+
+extern "C" {
+
+// Compiles `source` in the isolate's current context. When it does not
+// compile, stores the 1-based line and column V8 reported for the syntax
+// error and returns 1, otherwise returns 0.
+int serverless_syntax_error_position(v8::Isolate *isolate,
+                                     const char *source,
+                                     size_t length,
+                                     int *line,
+                                     int *column) {
+  v8::HandleScope scope(isolate);
+  v8::Local<v8::Context> context = isolate->GetCurrentContext();
+  if (context.IsEmpty()) {
+    return 0;
+  }
+
+  v8::TryCatch try_catch(isolate);
+  v8::Local<v8::String> code;
+  if (!v8::String::NewFromUtf8(isolate, source, v8::NewStringType::kNormal,
+                               static_cast<int>(length))
+           .ToLocal(&code)) {
+    return 0;
+  }
+  if (!v8::Script::Compile(context, code).IsEmpty()) {
+    return 0;
+  }
+
+  v8::Local<v8::Message> message = try_catch.Message();
+  if (message.IsEmpty()) {
+    return 0;
+  }
+  int l = message->GetLineNumber(context).FromMaybe(0);
+  int c = message->GetStartColumn(context).FromMaybe(-1);
+  if (l <= 0 || c < 0) {
+    return 0;
+  }
+  *line = l;
+  *column = c + 1;
+  return 1;
+}
+
+// Mirrors `ScriptError` in `native.rs`: V8's message, allocated with
+// `malloc`, and the position of the first frame of its stack trace, 0 without
+// one. `message` stays null when the script was terminated.
+struct ScriptError {
+  char *message;
+  int line;
+  int column;
+};
+
+static void catch_error(v8::Isolate *isolate, v8::TryCatch &try_catch,
+                        ScriptError *error) {
+  v8::Local<v8::Message> message = try_catch.Message();
+  if (try_catch.HasTerminated() || message.IsEmpty()) {
+    return;
+  }
+  v8::String::Utf8Value text(isolate, message->Get());
+  error->message = strdup(*text ? *text : "Unknown error");
+
+  v8::Local<v8::StackTrace> trace = message->GetStackTrace();
+  if (!trace.IsEmpty() && trace->GetFrameCount() > 0) {
+    v8::Local<v8::StackFrame> frame = trace->GetFrame(isolate, 0);
+    error->line = frame->GetLineNumber();
+    error->column = frame->GetColumn();
+  }
+}
+
+// Compiles `source` for any context of `isolate`, returns null and fills
+// `error` when it does not compile. With a `cache` produced by
+// `serverless_script_code_cache` the source is not parsed again, unless V8
+// rejects the cache, which sets `rejected`. The caller has to make a context
+// current and free the script with `serverless_script_free`.
+v8::Persistent<v8::UnboundScript> *serverless_script_compile(
+    v8::Isolate *isolate, const char *source, size_t length,
+    const uint8_t *cache, size_t cache_length, int *rejected,
+    ScriptError *error) {
+  v8::Isolate::Scope isolate_scope(isolate);
+  v8::HandleScope scope(isolate);
+  v8::Local<v8::Context> context = isolate->GetCurrentContext();
+  if (context.IsEmpty()) {
+    error->message = strdup("No current context");
+    return nullptr;
+  }
+
+  v8::TryCatch try_catch(isolate);
+  v8::Local<v8::String> code;
+  if (!v8::String::NewFromUtf8(isolate, source, v8::NewStringType::kNormal,
+                               static_cast<int>(length))
+           .ToLocal(&code)) {
+    catch_error(isolate, try_catch, error);
+    return nullptr;
+  }
+  v8::ScriptCompiler::CompileOptions options =
+      v8::ScriptCompiler::kNoCompileOptions;
+  v8::ScriptCompiler::CachedData *cached = nullptr;
+  if (cache != nullptr) {
+    // owned by `compiled_source`, the buffer stays the caller's
+    cached = new v8::ScriptCompiler::CachedData(cache,
+                                                static_cast<int>(cache_length));
+    options = v8::ScriptCompiler::kConsumeCodeCache;
+  }
+  v8::ScriptCompiler::Source compiled_source(code, cached);
+  v8::Local<v8::UnboundScript> script;
+  if (!v8::ScriptCompiler::CompileUnboundScript(isolate, &compiled_source,
+                                                options)
+           .ToLocal(&script)) {
+    catch_error(isolate, try_catch, error);
+    return nullptr;
+  }
+  *rejected = cached != nullptr && cached->rejected ? 1 : 0;
+  return new v8::Persistent<v8::UnboundScript>(isolate, script);
+}
+
+// Runs `script`'s top level code in the current context. Returns 0 and fills
+// `error` when it throws or is terminated, 1 otherwise.
+int serverless_script_run(v8::Isolate *isolate,
+                          v8::Persistent<v8::UnboundScript> *script,
+                          ScriptError *error) {
+  v8::Isolate::Scope isolate_scope(isolate);
+  v8::HandleScope scope(isolate);
+  v8::Local<v8::Context> context = isolate->GetCurrentContext();
+  if (context.IsEmpty()) {
+    error->message = strdup("No current context");
+    return 0;
+  }
+
+  v8::TryCatch try_catch(isolate);
+  v8::Local<v8::Script> bound = script->Get(isolate)->BindToCurrentContext();
+  if (bound->Run(context).IsEmpty()) {
+    catch_error(isolate, try_catch, error);
+    return 0;
+  }
+  return 1;
+}
+
+// V8's code cache for `script`, including the functions compiled since it
+// was, in a buffer allocated with `malloc`. Returns null when V8 produced none.
+uint8_t *serverless_script_code_cache(v8::Isolate *isolate,
+                                      v8::Persistent<v8::UnboundScript> *script,
+                                      size_t *length) {
+  v8::Isolate::Scope isolate_scope(isolate);
+  v8::HandleScope scope(isolate);
+  v8::ScriptCompiler::CachedData *cached =
+      v8::ScriptCompiler::CreateCodeCache(script->Get(isolate));
+  if (cached == nullptr) {
+    return nullptr;
+  }
+  uint8_t *data = static_cast<uint8_t *>(malloc(cached->length));
+  if (data != nullptr) {
+    memcpy(data, cached->data, cached->length);
+    *length = cached->length;
+  }
+  delete cached;
+  return data;
+}
+
+// The version of the linked V8, a code cache is only valid for the build
+// that produced it.
+const char *serverless_v8_version() { return v8::V8::GetVersion(); }
+
+void serverless_script_free(v8::Persistent<v8::UnboundScript> *script) {
+  script->Reset();
+  delete script;
+}
+
+// Mirrors `HeapWatch` in `native.rs`: the isolate watched, how many bytes its
+// heap may use and whether the script was terminated for using more.
+struct HeapWatch {
+  v8::Isolate *isolate;
+  size_t limit;
+  int exceeded;
+};
+
+static void stop_script(HeapWatch *watch) {
+  if (!watch->exceeded) {
+    watch->exceeded = 1;
+    watch->isolate->TerminateExecution();
+  }
+}
+
+// Runs after every garbage collection, what is still in use counts against
+// the limit.
+static void check_heap(v8::Isolate *isolate, v8::GCType, v8::GCCallbackFlags,
+                       void *data) {
+  HeapWatch *watch = static_cast<HeapWatch *>(data);
+  v8::HeapStatistics stats;
+  isolate->GetHeapStatistics(&stats);
+  if (stats.used_heap_size() > watch->limit) {
+    stop_script(watch);
+  }
+}
+
+// V8 is about to run out of heap, which would abort the process. The script
+// is terminated and the heap may grow enough to unwind it.
+static size_t near_heap_limit(void *data, size_t current_heap_limit, size_t) {
+  stop_script(static_cast<HeapWatch *>(data));
+  return current_heap_limit * 2;
+}
+
+// Terminates the script running in `watch->isolate` once its heap grows past
+// `watch->limit`. `watch` has to outlive the isolate or be passed to
+// `serverless_unwatch_heap`.
+void serverless_watch_heap(HeapWatch *watch) {
+  watch->isolate->AddGCEpilogueCallback(check_heap, watch);
+  watch->isolate->AddNearHeapLimitCallback(near_heap_limit, watch);
+}
+
+void serverless_unwatch_heap(HeapWatch *watch) {
+  watch->isolate->RemoveNearHeapLimitCallback(near_heap_limit, 0);
+  watch->isolate->RemoveGCEpilogueCallback(check_heap, watch);
+}
+
+// Stops the JavaScript running in `isolate`. The one isolate call V8 allows
+// from a thread other than the one running it.
+void serverless_terminate_execution(v8::Isolate *isolate) {
+  isolate->TerminateExecution();
+}
+
+}
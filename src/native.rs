@@ -0,0 +1,236 @@
+//! Calls into V8 the `v8` crate does not bind, implemented against V8's C++
+//! API in `native.cc`.
+
+extern crate libc;
+extern crate v8;
+
+use std::ffi::CStr;
+use std::ptr;
+use std::slice;
+
+use self::libc::{c_char, c_int, c_void, size_t};
+
+extern "C" {
+    fn serverless_syntax_error_position(isolate: *mut c_void,
+                                        source: *const c_char,
+                                        length: size_t,
+                                        line: *mut c_int,
+                                        column: *mut c_int) -> c_int;
+    fn serverless_terminate_execution(isolate: *mut c_void);
+    fn serverless_script_compile(isolate: *mut c_void,
+                                 source: *const c_char,
+                                 length: size_t,
+                                 cache: *const u8,
+                                 cache_length: size_t,
+                                 rejected: *mut c_int,
+                                 error: *mut ScriptError) -> *mut c_void;
+    fn serverless_script_run(isolate: *mut c_void, script: *mut c_void, error: *mut ScriptError) -> c_int;
+    fn serverless_script_code_cache(isolate: *mut c_void, script: *mut c_void, length: *mut size_t) -> *mut u8;
+    fn serverless_v8_version() -> *const c_char;
+    fn serverless_script_free(script: *mut c_void);
+    fn serverless_watch_heap(watch: *mut HeapWatch);
+    fn serverless_unwatch_heap(watch: *mut HeapWatch);
+}
+
+/// Filled by `native.cc` when a script fails.
+#[repr(C)]
+struct ScriptError {
+    message: *mut c_char,
+    line: c_int,
+    column: c_int,
+}
+
+impl ScriptError {
+    fn new() -> ScriptError {
+        ScriptError { message: ptr::null_mut(), line: 0, column: 0 }
+    }
+
+    /// The error the `v8` crate reports for the same failure, an exception's
+    /// message and the position of its first stack frame.
+    fn into_v8(self) -> v8::error::Error {
+        if self.message.is_null() {
+            return "Execution terminated".into();
+        }
+        let message = unsafe {
+            let message = CStr::from_ptr(self.message).to_string_lossy().into_owned();
+            libc::free(self.message as *mut c_void);
+            message
+        };
+        let frames =
+            if self.line > 0 {
+                vec![v8::error::CapturedStackFrame {
+                    line: self.line as u32,
+                    column: self.column as u32,
+                    script_name: None,
+                    function_name: None,
+                    is_eval: false,
+                    is_constructor: false,
+                }]
+            } else {
+                Vec::new()
+            };
+        v8::error::ErrorKind::Javascript(message, v8::error::CapturedStackTrace { frames: frames }).into()
+    }
+}
+
+/// A script compiled once for an isolate and run in any of its contexts,
+/// what the `v8` crate's `Script`, bound to the context it was compiled in,
+/// can not do.
+pub struct CompiledScript {
+    raw: *mut c_void,
+    isolate: v8::Isolate,
+}
+
+impl CompiledScript {
+    /// Compiles `code`, the caller has to make one of the isolate's
+    /// contexts current.
+    pub fn compile(isolate: &v8::Isolate, code: &str) -> v8::error::Result<CompiledScript> {
+        CompiledScript::compile_with_cache(isolate, code, None).map(|(script, _)| script)
+    }
+
+    /// Compiles `code` from a `cache` returned by `code_cache`, which spares
+    /// parsing it. Also returns whether V8 rejected the cache and compiled
+    /// the source instead.
+    pub fn compile_with_cache(isolate: &v8::Isolate, code: &str, cache: Option<&[u8]>) -> v8::error::Result<(CompiledScript, bool)> {
+        let mut error = ScriptError::new();
+        let mut rejected: c_int = 0;
+        let (data, length) = cache.map_or((ptr::null(), 0), |c| (c.as_ptr(), c.len()));
+        let raw = unsafe {
+            serverless_script_compile(isolate.as_raw() as *mut c_void,
+                                      code.as_ptr() as *const c_char,
+                                      code.len() as size_t,
+                                      data,
+                                      length as size_t,
+                                      &mut rejected,
+                                      &mut error)
+        };
+        if raw.is_null() {
+            return Err(error.into_v8());
+        }
+        Ok((CompiledScript { raw: raw, isolate: isolate.clone() }, rejected != 0))
+    }
+
+    /// V8's code cache for the script, with the functions compiled since,
+    /// `None` when V8 produced none.
+    pub fn code_cache(&self) -> Option<Vec<u8>> {
+        let mut length: size_t = 0;
+        unsafe {
+            let data = serverless_script_code_cache(self.isolate.as_raw() as *mut c_void, self.raw, &mut length);
+            if data.is_null() {
+                return None;
+            }
+            let cache = slice::from_raw_parts(data, length as usize).to_vec();
+            libc::free(data as *mut c_void);
+            Some(cache)
+        }
+    }
+
+    /// Runs the script's top level code in the current context.
+    pub fn run(&self) -> v8::error::Result<()> {
+        let mut error = ScriptError::new();
+        match unsafe { serverless_script_run(self.isolate.as_raw() as *mut c_void, self.raw, &mut error) } {
+            1 => Ok(()),
+            _ => Err(error.into_v8()),
+        }
+    }
+}
+
+impl Drop for CompiledScript {
+    fn drop(&mut self) {
+        unsafe { serverless_script_free(self.raw) };
+    }
+}
+
+/// Shared with `native.cc`, which sets `exceeded`.
+#[repr(C)]
+struct HeapWatch {
+    isolate: *mut c_void,
+    limit: size_t,
+    exceeded: c_int,
+}
+
+/// Caps the heap of an isolate. Its script is terminated once a garbage
+/// collection leaves more than the limit in use, or when V8 itself is about
+/// to run out of heap, which would abort the whole process.
+pub struct HeapLimit {
+    watch: *mut HeapWatch,
+    /// Kept until the callbacks are removed.
+    _isolate: v8::Isolate,
+}
+
+impl HeapLimit {
+    pub fn new(isolate: &v8::Isolate, bytes: usize) -> HeapLimit {
+        let watch = Box::into_raw(Box::new(HeapWatch {
+            isolate: isolate.as_raw() as *mut c_void,
+            limit: bytes as size_t,
+            exceeded: 0,
+        }));
+        unsafe { serverless_watch_heap(watch) };
+        HeapLimit { watch: watch, _isolate: isolate.clone() }
+    }
+
+    /// Changes the limit for the next script the isolate runs.
+    pub fn set(&mut self, bytes: usize) {
+        unsafe { (*self.watch).limit = bytes as size_t };
+    }
+
+    /// Whether the script was terminated for using too much heap.
+    pub fn exceeded(&self) -> bool {
+        unsafe { (*self.watch).exceeded != 0 }
+    }
+}
+
+impl Drop for HeapLimit {
+    fn drop(&mut self) {
+        unsafe {
+            serverless_unwatch_heap(self.watch);
+            drop(Box::from_raw(self.watch));
+        }
+    }
+}
+
+/// An isolate's raw pointer, all `TerminateExecution` needs. Unlike
+/// `v8::Isolate` it leaves the isolate's reference count, which is not
+/// atomic, alone and can be handed to another thread.
+#[derive(Clone, Copy, Debug)]
+pub struct IsolateHandle(*mut c_void);
+
+unsafe impl Send for IsolateHandle {}
+unsafe impl Sync for IsolateHandle {}
+
+impl IsolateHandle {
+    pub fn new(isolate: &v8::Isolate) -> IsolateHandle {
+        IsolateHandle(isolate.as_raw() as *mut c_void)
+    }
+
+    /// Stops the JavaScript running in the isolate, from any thread. The
+    /// caller has to make sure the isolate has not been disposed yet.
+    pub unsafe fn terminate_execution(&self) {
+        serverless_terminate_execution(self.0)
+    }
+}
+
+/// Compiles `code` in the isolate's current context and returns the line and
+/// column (both 1-based) of its syntax error, `None` when it compiles. The
+/// caller has to make the context current.
+pub fn syntax_error_position(isolate: &v8::Isolate, code: &str) -> Option<(u32, u32)> {
+    let mut line: c_int = 0;
+    let mut column: c_int = 0;
+    let found = unsafe {
+        serverless_syntax_error_position(isolate.as_raw() as *mut c_void,
+                                         code.as_ptr() as *const c_char,
+                                         code.len() as size_t,
+                                         &mut line,
+                                         &mut column)
+    };
+    if found == 1 {
+        Some((line as u32, column as u32))
+    } else {
+        None
+    }
+}
+
+/// The version of the V8 the shim is linked against.
+pub fn v8_version() -> String {
+    unsafe { CStr::from_ptr(serverless_v8_version()).to_string_lossy().into_owned() }
+}
@@ -0,0 +1,310 @@
+extern crate futures_cpupool;
+extern crate hyper;
+extern crate native_tls;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_tls;
+extern crate url;
+
+use futures::{future, Future, Poll};
+use futures::future::{Either, Loop};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use self::futures_cpupool::CpuPool;
+use self::hyper::Uri;
+use self::hyper::client::{Client, Service};
+use self::native_tls::TlsConnector;
+use self::tokio_core::net::TcpStream;
+use self::tokio_core::reactor::{Handle, Timeout};
+use self::tokio_io::{AsyncRead, AsyncWrite};
+use self::tokio_tls::{TlsConnectorExt, TlsStream};
+use self::url::Url;
+
+use egress::Egress;
+
+/// Redirects followed for a single request.
+pub const MAX_REDIRECTS: usize = 10;
+
+/// Threads resolving the names of upstreams, off the event loops.
+const DNS_THREADS: usize = 4;
+
+/// Longest response to a proxy's `CONNECT` read before giving up.
+const MAX_TUNNEL_HEAD: usize = 8192;
+
+/// Defaults of the requests lambdas send with `http.request` and `fetch()`.
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    /// How long a request may take unless the call sets its own
+    /// (`HTTP_TIMEOUT_MS`).
+    pub timeout: Duration,
+    /// How long opening a connection may take (`HTTP_CONNECT_TIMEOUT_MS`).
+    pub connect_timeout: Duration,
+    /// How long an idle connection is kept for the next request
+    /// (`HTTP_IDLE_TIMEOUT_MS`), zero closes it after every request.
+    pub idle_timeout: Duration,
+    /// Sent unless a lambda sets its own (`HTTP_USER_AGENT`).
+    pub user_agent: String,
+    /// Proxy for every outbound request (`HTTP_PROXY`).
+    pub proxy: Option<String>,
+}
+
+/// Where the proxy listens.
+#[derive(Clone, Debug)]
+struct Proxy {
+    host: String,
+    port: u16,
+}
+
+/// What the outbound HTTP clients of every executor share. Each event loop
+/// builds its own client, which keeps its connections alive between
+/// invocations.
+pub struct HttpClients {
+    config: HttpConfig,
+    proxy: Option<Proxy>,
+    dns: CpuPool,
+}
+
+impl HttpClients {
+    /// Fails when the proxy is not a valid `http` URL.
+    pub fn new(config: HttpConfig) -> Result<HttpClients, String> {
+        let proxy =
+            match config.proxy {
+                Some(ref raw) => {
+                    let url = Url::parse(raw).map_err(|e| format!("Invalid HTTP_PROXY: {}", e))?;
+                    match (url.scheme(), url.host_str(), url.port_or_known_default()) {
+                        ("http", Some(host), Some(port)) => Some(Proxy { host: host.to_string(), port: port }),
+                        _ => return Err("Invalid HTTP_PROXY: expected http://host:port".to_string()),
+                    }
+                },
+                None => None,
+            };
+        Ok(HttpClients { config: config, proxy: proxy, dns: CpuPool::new(DNS_THREADS) })
+    }
+
+    pub fn config(&self) -> &HttpConfig {
+        &self.config
+    }
+
+    /// Whether requests go through `HTTP_PROXY`, plain `http` ones then name
+    /// their target in the request line.
+    pub fn proxied(&self) -> bool {
+        self.proxy.is_some()
+    }
+
+    /// Opens connections on the event loop of `handle` to the addresses
+    /// `egress` allows.
+    pub fn connector(&self, handle: &Handle, egress: Arc<Mutex<Egress>>) -> Result<Connector, String> {
+        let tls = TlsConnector::builder()
+            .and_then(|b| b.build())
+            .map_err(|e| format!("Unable to create HTTP client: {}", e))?;
+        Ok(Connector {
+            handle: handle.clone(),
+            connect_timeout: self.config.connect_timeout,
+            proxy: self.proxy.clone(),
+            dns: self.dns.clone(),
+            tls: tls,
+            egress: egress,
+        })
+    }
+
+    /// A client with a new pool of connections, driven by the event loop of
+    /// `handle`.
+    pub fn client(&self, connector: Connector, handle: &Handle) -> Client<Connector> {
+        let idle = self.config.idle_timeout;
+        Client::configure()
+            .connector(connector)
+            .keep_alive(idle > Duration::from_secs(0))
+            .keep_alive_timeout(Some(idle))
+            .build(handle)
+    }
+}
+
+/// A connection to an upstream, encrypted for `https`.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Stream {}
+
+impl AsyncWrite for Stream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            Stream::Plain(ref mut s) => AsyncWrite::shutdown(s),
+            Stream::Tls(ref mut s) => AsyncWrite::shutdown(s),
+        }
+    }
+}
+
+fn other<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Opens the connections of a client: resolves the upstream on the DNS
+/// threads and checks its addresses against the egress policy, connects to
+/// one of those addresses within the connect timeout, tunnels `https`
+/// through the proxy and sets up TLS for the upstream's name. A denied
+/// connection fails with `PermissionDenied`.
+#[derive(Clone)]
+pub struct Connector {
+    handle: Handle,
+    connect_timeout: Duration,
+    proxy: Option<Proxy>,
+    dns: CpuPool,
+    tls: TlsConnector,
+    /// The policy of the invocation running on the loop.
+    egress: Arc<Mutex<Egress>>,
+}
+
+fn resolve(dns: &CpuPool, host: String, port: u16) -> Box<Future<Item=Vec<SocketAddr>, Error=io::Error>> {
+    Box::new(dns.spawn_fn(move || {
+        let addresses: Vec<SocketAddr> = (host.as_str(), port).to_socket_addrs()?.collect();
+        if addresses.is_empty() {
+            return Err(other(format!("{} has no addresses", host)));
+        }
+        Ok(addresses)
+    }))
+}
+
+/// Tries `addresses` in order until one accepts the connection within
+/// `timeout`.
+fn connect(handle: Handle, timeout: Duration, addresses: Vec<SocketAddr>) -> Box<Future<Item=TcpStream, Error=io::Error>> {
+    Box::new(future::loop_fn((addresses, 0), move |(addresses, i)| {
+        let timer =
+            match Timeout::new(timeout, &handle) {
+                Ok(t) => t,
+                Err(e) => return Either::A(future::err(e)),
+            };
+        let attempt = TcpStream::connect(&addresses[i], &handle)
+            .select2(timer)
+            .then(|res| match res {
+                Ok(Either::A((stream, _))) => Ok(stream),
+                Ok(Either::B(_)) => Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")),
+                Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+            });
+        Either::B(attempt.then(move |res| match res {
+            Ok(stream) => Ok(Loop::Break(stream)),
+            Err(_) if i + 1 < addresses.len() => Ok(Loop::Continue((addresses, i + 1))),
+            Err(e) => Err(e),
+        }))
+    }))
+}
+
+/// Asks the proxy for a tunnel to `host:port` and waits for it to agree.
+fn tunnel(stream: TcpStream, host: String, port: u16) -> Box<Future<Item=TcpStream, Error=io::Error>> {
+    let authority = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    let connect = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority);
+    Box::new(tokio_io::io::write_all(stream, connect.into_bytes())
+        // byte by byte, nothing after the head may be consumed
+        .and_then(|(stream, _)| future::loop_fn((stream, Vec::new()), |(stream, mut head)| {
+            tokio_io::io::read_exact(stream, [0u8; 1]).and_then(move |(stream, byte)| {
+                head.push(byte[0]);
+                if head.ends_with(b"\r\n\r\n") {
+                    Ok(Loop::Break((stream, head)))
+                } else if head.len() >= MAX_TUNNEL_HEAD {
+                    Err(other("the proxy's response is too long"))
+                } else {
+                    Ok(Loop::Continue((stream, head)))
+                }
+            })
+        }))
+        .and_then(|(stream, head)| {
+            if head.starts_with(b"HTTP/1.1 200") || head.starts_with(b"HTTP/1.0 200") {
+                Ok(stream)
+            } else {
+                Err(other("the proxy refused the tunnel"))
+            }
+        }))
+}
+
+impl Service for Connector {
+    type Request = Uri;
+    type Response = Stream;
+    type Error = io::Error;
+    type Future = Box<Future<Item=Stream, Error=io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let https = uri.scheme() == Some("https");
+        let host =
+            match uri.host() {
+                Some(h) => h.to_string(),
+                None => return Box::new(future::err(other("the URL has no host"))),
+            };
+        let port = uri.port().unwrap_or(if https { 443 } else { 80 });
+
+        // the address checked is the one connected to, the name is not
+        // resolved again
+        let egress = lock(&self.egress).clone();
+        let denied = host.clone();
+        let vetted = resolve(&self.dns, host.clone(), port).and_then(move |addresses| {
+            let ips: Vec<IpAddr> = addresses.iter().map(|a| a.ip()).collect();
+            egress.check_addresses(&ips)
+                .map(|_| addresses)
+                .map_err(|e| {
+                    warn!("Egress denied for {}: {}", denied, e);
+                    io::Error::new(io::ErrorKind::PermissionDenied, format!("Egress denied: {}", e))
+                })
+        });
+        let (addresses, tunneled): (Box<Future<Item=Vec<SocketAddr>, Error=io::Error>>, bool) =
+            match self.proxy {
+                // the proxy resolves the name on its own
+                Some(ref proxy) => {
+                    let proxy = resolve(&self.dns, proxy.host.clone(), proxy.port);
+                    (Box::new(vetted.and_then(move |_| proxy)), https)
+                },
+                None => (Box::new(vetted), false),
+            };
+
+        let handle = self.handle.clone();
+        let timeout = self.connect_timeout;
+        let connect = addresses.and_then(move |addresses| connect(handle, timeout, addresses));
+        let tls = self.tls.clone();
+        Box::new(connect
+            .and_then(move |stream| -> Box<Future<Item=Stream, Error=io::Error>> {
+                let stream =
+                    if tunneled {
+                        tunnel(stream, host.clone(), port)
+                    } else {
+                        Box::new(future::ok(stream))
+                    };
+                if !https {
+                    return Box::new(stream.map(Stream::Plain));
+                }
+                Box::new(stream.and_then(move |stream| {
+                    tls.connect_async(&host, stream).map(Stream::Tls).map_err(other)
+                }))
+            }))
+    }
+}
@@ -0,0 +1,128 @@
+(function (global) {
+  var pending = {};
+
+  function headerList(source) {
+    var headers = [];
+    source = source || {};
+    if (Array.isArray(source)) {
+      source.forEach(function (h) { headers.push([String(h[0]), String(h[1])]); });
+    } else if (source instanceof Headers) {
+      source.forEach(function (value, name) { headers.push([name, value]); });
+    } else {
+      Object.keys(source).forEach(function (name) { headers.push([name, String(source[name])]); });
+    }
+    return headers;
+  }
+
+  // strings are sent as UTF-8, buffers as they are and other objects as JSON;
+  // bytes cross into Rust as a string of char codes below 256
+  function encodeBody(body, headers) {
+    if (body == null) {
+      return { body: null, binary: false };
+    }
+    if (body instanceof ArrayBuffer || ArrayBuffer.isView(body)) {
+      var bytes = body instanceof ArrayBuffer
+        ? new Uint8Array(body)
+        : new Uint8Array(body.buffer, body.byteOffset, body.byteLength);
+      var chunks = [];
+      for (var i = 0; i < bytes.length; i += 8192) {
+        chunks.push(String.fromCharCode.apply(null, bytes.subarray(i, i + 8192)));
+      }
+      return { body: chunks.join(""), binary: true };
+    }
+    if (typeof body === "object") {
+      var typed = headers.some(function (h) { return h[0].toLowerCase() === "content-type"; });
+      if (!typed) {
+        headers.push(["Content-Type", "application/json"]);
+      }
+      return { body: JSON.stringify(body), binary: false };
+    }
+    return { body: String(body), binary: false };
+  }
+
+  function decodeBytes(bytes) {
+    var buffer = new Uint8Array(bytes.length);
+    for (var i = 0; i < bytes.length; i++) {
+      buffer[i] = bytes.charCodeAt(i);
+    }
+    return buffer.buffer;
+  }
+
+  function outbound(uri, options) {
+    var headers = headerList(options.headers);
+    var body = encodeBody(options.body, headers);
+    return {
+      uri: String(uri),
+      method: String(options.method || "GET").toUpperCase(),
+      headers: headers,
+      body: body.body,
+      binary: body.binary
+    };
+  }
+
+  function Headers(list) {
+    var map = {};
+    list.forEach(function (h) {
+      var name = h[0].toLowerCase();
+      map[name] = name in map ? map[name] + ", " + h[1] : h[1];
+    });
+    this.get = function (name) {
+      name = String(name).toLowerCase();
+      return name in map ? map[name] : null;
+    };
+    this.has = function (name) { return String(name).toLowerCase() in map; };
+    this.forEach = function (fn) {
+      Object.keys(map).forEach(function (name) { fn(map[name], name); });
+    };
+  }
+
+  function FetchResponse(raw) {
+    this.status = raw.status;
+    this.statusText = raw.statusText;
+    this.ok = raw.status >= 200 && raw.status < 300;
+    this.headers = new Headers(raw.headers);
+    this.text = function () { return Promise.resolve(raw.text); };
+    this.json = function () { return Promise.resolve(raw.text).then(JSON.parse); };
+    this.arrayBuffer = function () {
+      return Promise.resolve().then(function () { return decodeBytes(raw.bytes); });
+    };
+  }
+
+  global.fetch = function (input, init) {
+    var request = outbound(input, init || {});
+    return new Promise(function (resolve, reject) {
+      pending[__fetch(request)] = { resolve: resolve, reject: reject };
+    });
+  };
+
+  global.__fetchDone = function (id, error, raw) {
+    var p = pending[id];
+    if (!p) {
+      return;
+    }
+    delete pending[id];
+    if (error !== null) {
+      p.reject(new TypeError("fetch failed: " + error));
+    } else {
+      p.resolve(new FetchResponse(raw));
+    }
+  };
+
+  // `http.request`, blocks until the whole response arrived
+  global.__httpRequest = function (options) {
+    options = options || {};
+    var request = outbound(options.uri, options);
+    request.timeout = options.timeout == null ? null : Math.max(0, Math.floor(Number(options.timeout)));
+    request.followRedirects = options.followRedirects !== false;
+
+    var raw = __http(request);
+    return {
+      status: raw.status,
+      statusText: raw.statusText,
+      headers: raw.headers,
+      body: raw.text,
+      json: function () { return JSON.parse(raw.text); },
+      arrayBuffer: function () { return decodeBytes(raw.bytes); }
+    };
+  };
+})(this);
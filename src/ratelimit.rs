@@ -0,0 +1,470 @@
+use actix_web::{Error as ActixError, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::{Middleware, Response, Started};
+use futures::{future, Future};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use concurrency::Limiter;
+use db::{AppState, Dispatch, GetLambda};
+use error::Error;
+use executor::RateLimitKey;
+use metrics::Metrics;
+use request::{LambdaPath, Request};
+use runtime;
+
+/// Buckets kept before the ones that are full again, and so carry no state,
+/// are dropped, and then the least recently used ones.
+const MAX_BUCKETS: usize = 10000;
+/// How often buckets are dropped at most, every sweep goes through all of
+/// them.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What identifies a client for rate limiting.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeySource {
+    /// The address of the connecting peer.
+    Ip,
+    /// The value of a request header, e.g. an API key.
+    Header(String),
+    /// The lambda's `rateLimitKey(request)` function.
+    Script,
+}
+
+impl KeySource {
+    /// `ip`, `header:<name>` or `script`
+    pub fn parse(raw: &str) -> Option<KeySource> {
+        match raw {
+            "ip" => Some(KeySource::Ip),
+            "script" => Some(KeySource::Script),
+            _ if raw.starts_with("header:") && raw.len() > 7 => Some(KeySource::Header(raw[7..].to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// `limit` requests per minute for each client, as a token bucket that
+/// holds a minute's worth of requests.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub limit: u32,
+    pub key: KeySource,
+}
+
+impl Rule {
+    pub fn new(limit: Option<i32>, key: &Option<String>) -> Option<Rule> {
+        let limit = limit.filter(|l| *l > 0)?;
+        let key = key.as_ref().and_then(|k| KeySource::parse(k)).unwrap_or(KeySource::Ip);
+        Some(Rule { limit: limit as u32, key: key })
+    }
+
+    fn per_second(&self) -> f64 {
+        self.limit as f64 / 60.0
+    }
+}
+
+/// The rules a dispatched request falls under, each with the scope of its
+/// buckets.
+fn rules(dispatch: &Dispatch) -> Vec<(String, Rule)> {
+    let host = &dispatch.host_limits;
+    let lambda = &dispatch.route.lambda;
+
+    let mut rules = Vec::new();
+    if let Some(rule) = Rule::new(host.rate_limit, &host.rate_limit_key) {
+        rules.push((format!("host:{}", host.hostname), rule));
+    }
+    if let Some(rule) = Rule::new(lambda.rate_limit, &lambda.rate_limit_key) {
+        rules.push((format!("lambda:{}", lambda.id), rule));
+    }
+    rules
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// The rule's limit at the last refill.
+    limit: u32,
+}
+
+impl Bucket {
+    /// Whether the bucket has refilled by `now`, a new one would be the same.
+    fn full(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens + elapsed * self.limit as f64 / 60.0 >= self.limit as f64
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    swept: Option<Instant>,
+}
+
+impl Buckets {
+    /// Drops the buckets that are full again and then the least recently
+    /// used ones down to `max`.
+    fn sweep(&mut self, max: usize, now: Instant) {
+        self.swept = Some(now);
+        self.by_key.retain(|_, b| !b.full(now));
+        if self.by_key.len() > max {
+            let mut updated: Vec<Instant> = self.by_key.values().map(|b| b.updated).collect();
+            updated.sort();
+            let newest_dropped = updated[self.by_key.len() - max - 1];
+            self.by_key.retain(|_, b| b.updated > newest_dropped);
+        }
+    }
+}
+
+/// The outcome of a rate limit check, reported in the `RateLimit-*` headers.
+#[derive(Clone, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again.
+    pub reset: u64,
+    /// Seconds until the next request is allowed.
+    pub retry_after: u64,
+}
+
+impl Decision {
+    fn apply(&self, resp: &mut HttpResponse) {
+        let headers = resp.headers_mut();
+        headers.insert(HeaderName::from_static("ratelimit-limit"), HeaderValue::from(self.limit as u64));
+        headers.insert(HeaderName::from_static("ratelimit-remaining"), HeaderValue::from(self.remaining as u64));
+        headers.insert(HeaderName::from_static("ratelimit-reset"), HeaderValue::from(self.reset));
+    }
+}
+
+/// Token buckets of every client, kept in memory by each server instance.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+    metrics: Arc<Metrics>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn refill(bucket: &mut Bucket, rule: &Rule, now: Instant) {
+    let elapsed = now.duration_since(bucket.updated);
+    let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    bucket.tokens = (bucket.tokens + elapsed * rule.per_second()).min(rule.limit as f64);
+    bucket.updated = now;
+    bucket.limit = rule.limit;
+}
+
+impl RateLimiter {
+    pub fn new(metrics: Arc<Metrics>) -> RateLimiter {
+        RateLimiter { buckets: Mutex::new(Buckets::default()), metrics: metrics }
+    }
+
+    /// Checks every bucket in `checks` and takes a token from each only when
+    /// all of them have one, so a request denied by one rule does not use
+    /// up the others. Returns the most restrictive decision, `None` without
+    /// checks.
+    pub fn check(&self, checks: &[(String, &Rule)]) -> Option<Decision> {
+        self.decide(checks, true)
+    }
+
+    /// Like `check`, without taking any tokens.
+    pub fn peek(&self, checks: &[(String, &Rule)]) -> Option<Decision> {
+        self.decide(checks, false)
+    }
+
+    fn decide(&self, checks: &[(String, &Rule)], take: bool) -> Option<Decision> {
+        let now = Instant::now();
+        let mut buckets = lock(&self.buckets);
+        let due = buckets.swept.map_or(true, |swept| now.duration_since(swept) >= SWEEP_INTERVAL);
+        if buckets.by_key.len() > MAX_BUCKETS && due {
+            buckets.sweep(MAX_BUCKETS, now);
+        }
+        let buckets = &mut buckets.by_key;
+
+        for &(ref key, rule) in checks.iter() {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket { tokens: rule.limit as f64, updated: now, limit: rule.limit });
+            refill(bucket, rule, now);
+        }
+        let allowed = checks.iter().all(|&(ref key, _)| buckets[key].tokens >= 1.0);
+        if !allowed {
+            self.metrics.rate_limited.fetch_add(1, Ordering::Relaxed);
+        }
+
+        checks.iter()
+            .map(|&(ref key, rule)| {
+                let bucket = buckets.get_mut(key).expect("bucket refilled above");
+                if allowed && take {
+                    bucket.tokens -= 1.0;
+                }
+                Decision {
+                    allowed: allowed,
+                    limit: rule.limit,
+                    remaining: bucket.tokens.floor() as u32,
+                    reset: ((rule.limit as f64 - bucket.tokens) / rule.per_second()).ceil() as u64,
+                    retry_after: ((1.0 - bucket.tokens).max(0.0) / rule.per_second()).ceil().max(1.0) as u64,
+                }
+            })
+            // the bucket closest to running out is the one reported
+            .min_by_key(|d| (d.remaining, d.retry_after))
+    }
+}
+
+fn throttled(decision: &Decision) -> HttpResponse {
+    let mut resp = Error::Throttled {
+        message: "Rate limit exceeded".to_string(),
+        retry_after: decision.retry_after,
+    }.error_response();
+    decision.apply(&mut resp);
+    resp
+}
+
+fn client_key(source: &KeySource, req: &HttpRequest<AppState>, script_key: &Option<String>) -> String {
+    let ip = || req.peer_addr().map(|a| a.ip().to_string()).unwrap_or_else(|| "unknown".to_string());
+    match *source {
+        KeySource::Ip => ip(),
+        // clients without the header share the bucket of their address
+        KeySource::Header(ref name) => req.headers()
+            .get(name.as_str())
+            .and_then(|v| v.to_str().ok())
+            .map(|v| format!("header:{}", v))
+            .unwrap_or_else(ip),
+        KeySource::Script => script_key.as_ref()
+            .map(|k| format!("script:{}", k))
+            .unwrap_or_else(ip),
+    }
+}
+
+/// Applies the rate limits of lambdas and hostnames before a lambda runs and
+/// reports them in `RateLimit-Limit`, `RateLimit-Remaining` and
+/// `RateLimit-Reset`. The route resolved here is handed on to `exec_lambda`
+/// in the request extensions.
+pub struct RateLimit;
+
+impl Middleware<AppState> for RateLimit {
+    fn start(&self, req: &mut HttpRequest<AppState>) -> Result<Started, ActixError> {
+        if req.path().starts_with("/v1/") {
+            return Ok(Started::Done);
+        }
+
+        // a request that can not be routed is answered by `exec_lambda`
+        let name = LambdaPath { path: req.path().trim_left_matches('/').to_string() };
+        let request =
+            match Request::map(&name, req, None) {
+                Ok(r) => r,
+                Err(_) => return Ok(Started::Done),
+            };
+        let stage = request.header(::STAGE_HEADER);
+
+        let mut req = req.clone();
+        let fut = req.state()
+            .db
+            .send(GetLambda {
+                request: request,
+                stage: stage,
+            })
+            .from_err()
+            .and_then(move |res| -> Box<Future<Item=Option<HttpResponse>, Error=ActixError>> {
+                let dispatch =
+                    match res {
+                        Ok(d) => d,
+                        Err(_) => return Box::new(future::ok(None)),
+                    };
+                let rules = rules(&dispatch);
+                if rules.is_empty() {
+                    req.extensions_mut().insert(dispatch);
+                    return Box::new(future::ok(None));
+                }
+
+                // the address and header rules come first, a client over them
+                // costs no executor time
+                let denied = {
+                    let fixed: Vec<(String, &Rule)> = rules.iter()
+                        .filter(|&&(_, ref rule)| rule.key != KeySource::Script)
+                        .map(|&(ref scope, ref rule)| (format!("{}:{}", scope, client_key(&rule.key, &req, &None)), rule))
+                        .collect();
+                    req.state().rate_limiter.peek(&fixed).filter(|d| !d.allowed)
+                };
+                if let Some(d) = denied {
+                    return Box::new(future::ok(Some(throttled(&d))));
+                }
+
+                let script = rules.iter().any(|&(_, ref r)| r.key == KeySource::Script);
+                let script_key: Box<Future<Item=Option<String>, Error=Error>> =
+                    match (script, Request::map(&name, &req, None), dispatch.egress()) {
+                        (true, Ok(request), Ok(egress)) => {
+                            let lambda = &dispatch.route.lambda;
+                            let msg = RateLimitKey {
+                                lambda_id: lambda.id,
+                                version: lambda.version,
+                                code: lambda.code.clone(),
+                                code_cache: runtime::CodeCache::stored(lambda.code_cache.clone(), lambda.code_cache_tag.clone()),
+                                request: request.with_params(dispatch.route.params.clone()),
+                                limits: req.state().limits.for_lambda(lambda),
+                                egress: egress,
+                            };
+                            // `rateLimitKey` runs like an invocation, under the
+                            // concurrency limits and with an executor slot
+                            let queue = req.state().queue.clone();
+                            let js = req.state().js.clone();
+                            Box::new(Limiter::acquire_route(&req.state().limiter, &dispatch.host_limits, lambda)
+                                .and_then(move |permits| queue.acquire().map(|slot| (permits, slot)))
+                                .and_then(move |(permits, slot)| {
+                                    js.send(msg)
+                                        .from_err()
+                                        .map(move |res| {
+                                            drop(slot);
+                                            drop(permits);
+                                            res.unwrap_or_else(|e| {
+                                                warn!("rateLimitKey failed, limiting by address: {}", e);
+                                                None
+                                            })
+                                        })
+                                }))
+                        },
+                        _ => Box::new(future::ok(None)),
+                    };
+
+                Box::new(script_key.then(move |script_key| {
+                    let script_key =
+                        match script_key {
+                            Ok(k) => k,
+                            Err(e) => return Ok(Some(e.error_response())),
+                        };
+                    let checks: Vec<(String, &Rule)> = rules.iter()
+                        .map(|&(ref scope, ref rule)| (format!("{}:{}", scope, client_key(&rule.key, &req, &script_key)), rule))
+                        .collect();
+
+                    match req.state().rate_limiter.check(&checks) {
+                        Some(ref d) if !d.allowed => Ok(Some(throttled(d))),
+                        decision => {
+                            if let Some(d) = decision {
+                                req.extensions_mut().insert(d);
+                            }
+                            req.extensions_mut().insert(dispatch);
+                            Ok(None)
+                        },
+                    }
+                }))
+            });
+
+        Ok(Started::Future(Box::new(fut)))
+    }
+
+    fn response(&self, req: &mut HttpRequest<AppState>, mut resp: HttpResponse) -> Result<Response, ActixError> {
+        if let Some(decision) = req.extensions().get::<Decision>() {
+            decision.apply(&mut resp);
+        }
+        Ok(Response::Done(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(limit: u32) -> Rule {
+        Rule { limit: limit, key: KeySource::Ip }
+    }
+
+    #[test]
+    fn parses_key_sources() {
+        assert_eq!(KeySource::parse("ip"), Some(KeySource::Ip));
+        assert_eq!(KeySource::parse("script"), Some(KeySource::Script));
+        assert_eq!(KeySource::parse("header:X-Api-Key"), Some(KeySource::Header("X-Api-Key".to_string())));
+        assert_eq!(KeySource::parse("header:"), None);
+        assert_eq!(KeySource::parse("cookie"), None);
+    }
+
+    #[test]
+    fn refills_per_minute_up_to_limit() {
+        let rule = rule(60);
+        let now = Instant::now();
+        let mut bucket = Bucket { tokens: 0.0, updated: now - Duration::from_secs(10), limit: 60 };
+        refill(&mut bucket, &rule, now);
+        assert_eq!(bucket.tokens.round(), 10.0);
+
+        bucket.updated = now - Duration::from_secs(120);
+        refill(&mut bucket, &rule, now);
+        assert_eq!(bucket.tokens, 60.0);
+    }
+
+    #[test]
+    fn takes_tokens_until_empty() {
+        let limiter = RateLimiter::new(Arc::new(Metrics::default()));
+        let rule = rule(2);
+        let checks = vec![("a".to_string(), &rule)];
+
+        let first = limiter.check(&checks).unwrap();
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert!(limiter.check(&checks).unwrap().allowed);
+        let denied = limiter.check(&checks).unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.limit, 2);
+        assert!(denied.retry_after >= 1);
+        assert_eq!(limiter.metrics.rate_limited.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn denied_requests_take_no_tokens() {
+        let limiter = RateLimiter::new(Arc::new(Metrics::default()));
+        let (wide, narrow) = (rule(10), rule(1));
+        assert!(limiter.check(&[("narrow".to_string(), &narrow)]).unwrap().allowed);
+
+        let both = vec![("wide".to_string(), &wide), ("narrow".to_string(), &narrow)];
+        assert!(!limiter.check(&both).unwrap().allowed);
+        let wide_only = limiter.peek(&[("wide".to_string(), &wide)]).unwrap();
+        assert_eq!(wide_only.remaining, 10);
+    }
+
+    #[test]
+    fn peek_takes_no_tokens() {
+        let limiter = RateLimiter::new(Arc::new(Metrics::default()));
+        let rule = rule(1);
+        let checks = vec![("a".to_string(), &rule)];
+        assert!(limiter.peek(&checks).unwrap().allowed);
+        assert!(limiter.check(&checks).unwrap().allowed);
+        assert!(limiter.check(&[]).is_none());
+    }
+
+    #[test]
+    fn sweeps_full_buckets_then_the_oldest() {
+        let now = Instant::now();
+        let mut buckets = Buckets::default();
+        let bucket = |tokens: f64, age: u64| Bucket { tokens: tokens, updated: now - Duration::from_secs(age), limit: 60 };
+        buckets.by_key.insert("full".to_string(), bucket(0.0, 60));
+        buckets.by_key.insert("old".to_string(), bucket(0.0, 30));
+        buckets.by_key.insert("older".to_string(), bucket(0.0, 40));
+        buckets.by_key.insert("new".to_string(), bucket(0.0, 1));
+
+        buckets.sweep(2, now);
+        let mut kept: Vec<_> = buckets.by_key.keys().cloned().collect();
+        kept.sort();
+        assert_eq!(kept, vec!["new".to_string(), "old".to_string()]);
+        assert_eq!(buckets.swept, Some(now));
+    }
+
+    #[test]
+    fn sweeps_at_most_once_per_interval() {
+        let limiter = RateLimiter::new(Arc::new(Metrics::default()));
+        let rule = rule(1);
+        for i in 0..MAX_BUCKETS + 2 {
+            limiter.check(&[(i.to_string(), &rule)]);
+        }
+        // swept once there were too many, none of them is full again
+        let (swept, kept) = {
+            let buckets = lock(&limiter.buckets);
+            (buckets.swept, buckets.by_key.len())
+        };
+        assert!(swept.is_some());
+        assert!(kept <= MAX_BUCKETS + 1);
+
+        limiter.check(&[("next".to_string(), &rule)]);
+        let buckets = lock(&limiter.buckets);
+        assert_eq!(buckets.swept, swept);
+        assert_eq!(buckets.by_key.len(), kept + 1);
+    }
+}
@@ -2,19 +2,59 @@ extern crate url;
 extern crate serde;
 extern crate serde_json;
 extern crate v8;
-extern crate reqwest;
+extern crate hyper;
 
 use headers::{Header};
 use traits::{CanParse, ToString};
 use db::{AppState};
-use actix_web::{Path,HttpRequest,HttpMessage};
+use actix_web::{HttpRequest,HttpMessage};
+use actix_web::http::Uri;
 use functions;
+use error::Error;
+use self::url::percent_encoding::percent_decode;
 
-#[derive(Deserialize)]
+#[derive(Default, Deserialize)]
 pub struct LambdaPath {
     pub path: String,
 }
 
+#[derive(Deserialize)]
+pub struct AliasPath {
+    pub path: String,
+    pub alias: String,
+}
+
+impl AliasPath {
+    pub fn lambda_path(&self) -> LambdaPath {
+        LambdaPath { path: self.path.clone() }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VersionParams {
+    pub version: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TrafficRoute {
+    pub version: i32,
+    pub weight: i32,
+}
+
+/// Weighted routing between versions of a lambda. `sticky` is either
+/// `header:<name>` or `cookie:<name>`; requests sharing that value are
+/// always served by the same version.
+#[derive(Serialize, Deserialize)]
+pub struct TrafficConfig {
+    pub sticky: Option<String>,
+    pub routes: Vec<TrafficRoute>,
+}
+
+#[derive(Deserialize)]
+pub struct LambdaFilter {
+    pub hostname: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Method {
     GET,
@@ -22,7 +62,8 @@ pub enum Method {
     PUT,
     DELETE,
     PATCH,
-    OPTIONS
+    OPTIONS,
+    HEAD
 }
 
 impl Method {
@@ -34,18 +75,20 @@ impl Method {
             Method::PUT => "PUT",
             Method::PATCH => "PATCH",
             Method::OPTIONS => "OPTIONS",
+            Method::HEAD => "HEAD",
         }
         .to_string()
     }
 
-    pub fn to_reqwest(&self) -> reqwest::Method {
+    pub fn to_hyper(&self) -> hyper::Method {
         match self {
-            Method::GET => reqwest::Method::Get,
-            Method::POST => reqwest::Method::Post,
-            Method::DELETE => reqwest::Method::Delete,
-            Method::PUT => reqwest::Method::Put,
-            Method::PATCH => reqwest::Method::Patch,
-            Method::OPTIONS => reqwest::Method::Options,
+            Method::GET => hyper::Method::Get,
+            Method::POST => hyper::Method::Post,
+            Method::DELETE => hyper::Method::Delete,
+            Method::PUT => hyper::Method::Put,
+            Method::PATCH => hyper::Method::Patch,
+            Method::OPTIONS => hyper::Method::Options,
+            Method::HEAD => hyper::Method::Head,
         }
     }
 
@@ -55,12 +98,7 @@ impl Method {
 
     pub fn from_js(js: &v8::value::Value) -> Result<Method,String> {
         match js.clone().into_string() {
-            Some(s) => {
-                match s.value().as_str() {
-                    "GET" | "POST" | "PUT" | "DELETE" | "PATCH" | "OPTIONS" => Ok(Method::from_str(s.value()).unwrap()),
-                    _ => Err("Invalid Method".to_string())
-                }
-            },
+            Some(s) => Method::from_str(s.value()),
             None => Err("Invalid Method".to_string()),
         }
     }
@@ -73,17 +111,58 @@ impl Method {
             "DELETE" => Ok(Method::DELETE),
             "PATCH" => Ok(Method::PATCH),
             "OPTIONS" => Ok(Method::OPTIONS),
+            "HEAD" => Ok(Method::HEAD),
             _ => Err("Invalid Method".to_string())
         }
     }
 }
 
+/// Any HTTP method, the default for a lambda registration.
+pub const ANY_METHOD: &str = "*";
+
+/// Turns a comma separated method list into its canonical stored form:
+/// upper case, sorted and de-duplicated, or `*` when no list is given.
+pub fn normalize_methods(raw: Option<&str>) -> Result<String, String> {
+    let raw = raw.map(|r| r.trim()).unwrap_or("");
+    if raw.is_empty() || raw == ANY_METHOD {
+        return Ok(ANY_METHOD.to_string());
+    }
+
+    let mut methods = Vec::new();
+    for m in raw.split(',').map(|m| m.trim().to_uppercase()).filter(|m| !m.is_empty()) {
+        let method = Method::from_str(m)?.to_string();
+        if !methods.contains(&method) {
+            methods.push(method);
+        }
+    }
+    methods.sort();
+    Ok(methods.join(","))
+}
+
+/// The body of an outbound request as the prelude encodes it, `binary`
+/// bodies are strings of char codes below 256.
+pub fn body_from_js(isolate: &v8::isolate::Isolate, context: &v8::context::Context, js: &v8::value::Value) -> Option<Vec<u8>> {
+    let obj = js.clone().into_object()?;
+    let body = obj.get(&context, &v8::value::String::from_str(&isolate, "body")).into_string()?.value();
+    if obj.get(&context, &v8::value::String::from_str(&isolate, "binary")).is_true() {
+        Some(body.chars().map(|c| c as u32 as u8).collect())
+    } else {
+        Some(body.into_bytes())
+    }
+}
+
+/// Whether a stored method list accepts `method`.
+pub fn allows_method(methods: &str, method: &Method) -> bool {
+    methods == ANY_METHOD || methods.split(',').any(|m| m == method.to_string())
+}
+
 #[derive(Debug)]
 pub struct Request {
     uri: url::Url,
     method: Method,
     headers: Vec<Header>,
     body: Option<String>,
+    params: Vec<(String, String)>,
 }
 
 #[derive(Debug)]
@@ -92,58 +171,172 @@ pub struct RequestBuilder {
     err: Option<String>,
 }
 
+fn decoded(path: &str) -> String {
+    percent_decode(path.as_bytes()).decode_utf8_lossy().to_string()
+}
+
+/// The URI a request was sent to, its path and query as they were sent. The
+/// admin API names a lambda's path in its own path, that path is used
+/// instead.
+fn full_uri(host: &str, sent: &Uri, name: &LambdaPath) -> Result<url::Url, url::ParseError> {
+    let mut uri = url::Url::parse(&format!("http://{}/", host))?;
+    let path = format!("/{}", name.path);
+    if decoded(sent.path()) == decoded(&path) {
+        uri.set_path(sent.path());
+    } else {
+        uri.set_path(&path);
+    }
+    uri.set_query(sent.query());
+    Ok(uri)
+}
+
+/// The `Host` header a request was sent with. Lambdas and the hostnames the
+/// admin API changes are named by it alone, `X-Forwarded-Host` and other
+/// headers a client can add are not trusted.
+fn host_header<S>(req: &HttpRequest<S>) -> Result<String, Error> {
+    req.headers()
+        .get("host")
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.to_string())
+        .ok_or_else(|| Error::BadRequest("Missing Host header".to_string()))
+}
+
+/// The hostname a request is for, lower cased and without the port; the
+/// `host()` of the request `Request::map` builds from it.
+pub fn hostname<S>(req: &HttpRequest<S>) -> Result<String, Error> {
+    url::Url::parse(&format!("http://{}/", host_header(req)?))
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .ok_or_else(|| Error::BadRequest("Invalid Host header".to_string()))
+}
+
 impl Request {
     pub fn new<T: CanParse>(uri: T) -> RequestBuilder {
         RequestBuilder::new(uri)
     }
 
-    pub fn map(name: &Path<LambdaPath>, req: &HttpRequest<AppState>, body: Option<String>) -> Request {
-        // headers
+    pub fn map(name: &LambdaPath, req: &HttpRequest<AppState>, body: Option<String>) -> Result<Request, Error> {
+        // headers, values that are not valid UTF-8 can't be handed to JavaScript
         let mut headers = Vec::new();
         for (key, value) in req.headers().iter() {
-            headers.push(Header::new(key.as_str().to_string(), value.to_str().unwrap().to_string()));
+            if let Ok(v) = value.to_str() {
+                headers.push(Header::new(key.as_str().to_string(), v.to_string()));
+            }
         }
 
         // method
-        let method = Method::from_str(req.method().as_str()).unwrap();
+        let method = Method::from_str(req.method().as_str())
+            .map_err(|_| Error::BadRequest(format!("Unsupported method {}", req.method())))?;
 
         // uri
-        let host = req.headers().get("host").unwrap().to_str().unwrap().to_string();
-        let path = format!("/{}", name.path.clone());
-        let proto = "http".to_string();
-        let uri = url::Url::parse(format!("{}://{}{}", proto, host, path).as_str()).unwrap();
+        let host = host_header(req)?;
+        let uri = full_uri(&host, req.uri(), name)
+            .map_err(|_| Error::BadRequest("Invalid Host header".to_string()))?;
 
         Request::new(uri)
             .method(method)
             .headers(headers)
             .body(body)
             .build()
+            .map_err(Error::BadRequest)
     }
 
     pub fn path(&self) -> String {
+        decoded(self.uri.path())
+    }
+
+    pub fn raw_path(&self) -> String {
         self.uri.path().to_string()
     }
 
+    /// The query string as it was sent, without the `?`.
+    pub fn raw_query(&self) -> String {
+        self.uri.query().unwrap_or("").to_string()
+    }
+
+    /// The decoded query parameters, the values of a repeated key in the
+    /// order they were sent.
+    pub fn query(&self) -> Vec<(String, Vec<String>)> {
+        let mut query: Vec<(String, Vec<String>)> = Vec::new();
+        for (key, value) in self.uri.query_pairs() {
+            match query.iter().position(|&(ref k, _)| *k == key) {
+                Some(i) => query[i].1.push(value.into_owned()),
+                None => query.push((key.into_owned(), vec![value.into_owned()])),
+            }
+        }
+        query
+    }
+
+    /// Attaches the values captured by the route pattern.
+    pub fn with_params(mut self, params: Vec<(String, String)>) -> Request {
+        self.params = params;
+        self
+    }
+
     pub fn host(&self) -> String {
-        self.uri.host_str().unwrap().to_string()
+        self.uri.host_str().unwrap_or("").to_string()
+    }
+
+    pub fn uri(&self) -> &url::Url {
+        &self.uri
+    }
+
+    /// The request to send next when `status` redirects it to `location`.
+    /// 303, and 301 or 302 for anything but GET and HEAD, turn it into a GET
+    /// without a body; credentials are only sent again to the same origin,
+    /// so not to another host or port nor over plain `http`.
+    pub fn redirected(&self, status: u16, location: url::Url) -> (Request, bool) {
+        let keep_method =
+            match (status, &self.method) {
+                (307, _) | (308, _) => true,
+                (301, &Method::GET) | (302, &Method::GET) | (301, &Method::HEAD) | (302, &Method::HEAD) => true,
+                _ => false,
+            };
+        let same_origin = location.origin() == self.uri.origin();
+        let headers = self.headers.iter()
+            .filter(|h| keep_method || !h.name().eq_ignore_ascii_case("content-type"))
+            .filter(|h| same_origin || !(h.name().eq_ignore_ascii_case("authorization") || h.name().eq_ignore_ascii_case("cookie")))
+            .map(|h| Header::new(h.name(), h.value()))
+            .collect();
+        let method = if keep_method { Method::from_str(self.method.to_string()).unwrap_or(Method::GET) } else { Method::GET };
+        let request = Request { uri: location,
+                                method: method,
+                                headers: headers,
+                                body: None,
+                                params: Vec::new() };
+        (request, keep_method)
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
     }
 
     pub fn headers(&self) -> &Vec<Header> {
         &self.headers
     }
 
-    fn construct_headers(&self) -> reqwest::header::Headers {
-        let mut ret = reqwest::header::Headers::new();
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers.iter()
+            .find(|h| h.name().eq_ignore_ascii_case(name))
+            .map(|h| h.value())
+    }
+
+    fn construct_headers(&self) -> hyper::Headers {
+        let mut ret = hyper::Headers::new();
         for h in self.headers.iter() {
             ret.set_raw(h.name(), h.value());
         }
         ret
     }
 
-    pub fn to_reqwest(&self, client: &reqwest::Client) -> Result<reqwest::Request,reqwest::Error> {
-        client.request(self.method.to_reqwest(), self.uri.as_str())
-                      .headers(self.construct_headers())
-                      .build()
+    pub fn to_hyper(&self, body: Option<Vec<u8>>) -> Result<hyper::Request, String> {
+        let uri = self.uri.as_str().parse::<hyper::Uri>().map_err(|_| "Invalid Request!".to_string())?;
+        let mut ret = hyper::Request::new(self.method.to_hyper(), uri);
+        *ret.headers_mut() = self.construct_headers();
+        if let Some(b) = body {
+            ret.set_body(b);
+        }
+        Ok(ret)
     }
 
     pub fn js(&self, isolate: &v8::isolate::Isolate, context: &v8::context::Context) -> v8::value::Object {
@@ -178,6 +371,33 @@ impl Request {
             };
         ret.set(&context, &v8::value::String::from_str(&isolate, "body"), &body);
 
+        // route params
+        let params = v8::value::Object::new(&isolate, &context);
+        for &(ref key, ref value) in self.params.iter() {
+            params.set(&context, &v8::value::String::from_str(&isolate, key.as_str()),
+                &v8::value::String::from_str(&isolate, value.as_str()));
+        }
+        ret.set(&context, &v8::value::String::from_str(&isolate, "params"), &params);
+
+        // query, a repeated key becomes an array of its values
+        let query = v8::value::Object::new(&isolate, &context);
+        for (key, values) in self.query() {
+            let key = v8::value::String::from_str(&isolate, key.as_str());
+            if values.len() == 1 {
+                query.set(&context, &key, &v8::value::String::from_str(&isolate, values[0].as_str()));
+            } else {
+                let list = v8::value::Array::new(&isolate, &context, 0);
+                for (i, value) in values.iter().enumerate() {
+                    list.set(&context, &v8::value::Integer::new(&isolate, i as i32),
+                        &v8::value::String::from_str(&isolate, value.as_str()));
+                }
+                query.set(&context, &key, &list);
+            }
+        }
+        ret.set(&context, &v8::value::String::from_str(&isolate, "query"), &query);
+        ret.set(&context, &v8::value::String::from_str(&isolate, "rawQuery"),
+            &v8::value::String::from_str(&isolate, self.raw_query().as_str()));
+
         // functions
         let json = v8::value::Function::new(&isolate, &context, 0, Box::new(functions::parse_json));
         ret.set(&context, &v8::value::String::from_str(&isolate, "json"), &json);
@@ -195,7 +415,7 @@ impl Request {
                         // method
                         let method = 
                             match o.get(&context, &v8::value::String::from_str(&isolate, "method")).into_string() {
-                                Some(m) => Method::from_js(&m).unwrap(),
+                                Some(m) => Method::from_js(&m)?,
                                 None => Method::GET,
                             };
 
@@ -215,7 +435,7 @@ impl Request {
                                         let item = arr.get(&context, &v8::value::Integer::new(&isolate, count));
                                         if item.is_array() { 
                                             count += 1;
-                                            let head = Header::from_js(&isolate, &context, &item).unwrap();
+                                            let head = Header::from_js(&isolate, &context, &item)?;
                                             headers.push(head);
                                             true
                                         } else {
@@ -227,10 +447,14 @@ impl Request {
                                 None => Vec::new(),
                             };
 
-                        Ok(Request{ uri: url::Url::parse(uri.value().as_str()).unwrap(),
+                        let uri = url::Url::parse(uri.value().as_str())
+                            .map_err(|_| "Invalid Request: Invalid URI".to_string())?;
+
+                        Ok(Request{ uri: uri,
                                     method: method,
                                     headers: headers,
-                                    body: body })
+                                    body: body,
+                                    params: Vec::new() })
                     },
                     None => Err("Invalid Request: Missing URI".to_string()),
                 }
@@ -262,7 +486,8 @@ impl RequestBuilder {
                 Ok(u) => (Some(Request{ uri: u,
                                         method: Method::GET,
                                         headers: Vec::new(),
-                                        body: None }), None),
+                                        body: None,
+                                        params: Vec::new() }), None),
                 Err(_) => (None, Some("Invalid URL".to_string()))
             };
         RequestBuilder{ req: req, 
@@ -270,8 +495,8 @@ impl RequestBuilder {
     }
 
     #[inline]
-    fn req_mut(&mut self) -> &mut Request {
-        self.req.as_mut().expect("testing")
+    fn req_mut(&mut self) -> Option<&mut Request> {
+        self.req.as_mut()
     }
 
     #[inline]
@@ -279,14 +504,21 @@ impl RequestBuilder {
         &mut self.err
     }
 
-    pub fn build(&mut self) -> Request {
-        self.req.take().expect("testing")
+    /// Returns the first error recorded while building, if any.
+    pub fn build(&mut self) -> Result<Request, String> {
+        match (self.err.take(), self.req.take()) {
+            (Some(e), _) => Err(e),
+            (None, Some(req)) => Ok(req),
+            (None, None) => Err("Request already built".to_string()),
+        }
     }
 
     pub fn method<T: ToString>(&mut self, method: T) -> &mut RequestBuilder {
         match Method::from_str(method.get_string()) {
             Ok(m) => {
-                *self.req_mut().method_mut() = m;
+                if let Some(req) = self.req_mut() {
+                    *req.method_mut() = m;
+                }
                 self
             },
             Err(e) => {
@@ -297,7 +529,9 @@ impl RequestBuilder {
     }
 
     pub fn headers(&mut self, headers: Vec<Header>) -> &mut RequestBuilder {
-        *self.req_mut().headers_mut() = headers;
+        if let Some(req) = self.req_mut() {
+            *req.headers_mut() = headers;
+        }
         self
     }
 
@@ -307,7 +541,90 @@ impl RequestBuilder {
                 Some(b) => Some(b.get_string()),
                 None => None,
             };
-        *self.req_mut().body_mut() = body;
+        if let Some(req) = self.req_mut() {
+            *req.body_mut() = body;
+        }
         self
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str) -> Request {
+        Request::new(uri.to_string()).build().unwrap()
+    }
+
+    fn uri(sent: &str, name: &str) -> url::Url {
+        full_uri("example.com", &sent.parse::<Uri>().unwrap(), &LambdaPath { path: name.to_string() }).unwrap()
+    }
+
+    #[test]
+    fn keeps_the_path_and_query_as_sent() {
+        assert_eq!(uri("/a%2Fb/c%20d?x=%26&y", "a%2Fb/c%20d").as_str(), "http://example.com/a%2Fb/c%20d?x=%26&y");
+        assert_eq!(uri("/a%2Fb", "a/b").as_str(), "http://example.com/a%2Fb");
+        assert_eq!(uri("/items?", "items").query(), Some(""));
+        assert_eq!(uri("/items", "items").query(), None);
+    }
+
+    #[test]
+    fn admin_paths_name_the_lambda() {
+        let uri = uri("/v1/lambda/hello/world?methods=GET", "hello/world");
+        assert_eq!(uri.path(), "/hello/world");
+        assert_eq!(uri.query(), Some("methods=GET"));
+    }
+
+    #[test]
+    fn decodes_query_parameters() {
+        let req = request("http://example.com/search?q=a+b&tag=x&tag=y%20z&empty=&flag");
+        assert_eq!(req.query(), vec![
+            ("q".to_string(), vec!["a b".to_string()]),
+            ("tag".to_string(), vec!["x".to_string(), "y z".to_string()]),
+            ("empty".to_string(), vec!["".to_string()]),
+            ("flag".to_string(), vec!["".to_string()]),
+        ]);
+        assert_eq!(req.raw_query(), "q=a+b&tag=x&tag=y%20z&empty=&flag");
+    }
+
+    #[test]
+    fn no_query_is_empty() {
+        let req = request("http://example.com/search");
+        assert!(req.query().is_empty());
+        assert_eq!(req.raw_query(), "");
+    }
+
+    #[test]
+    fn decodes_the_path() {
+        let req = request("http://example.com/a%20b/c");
+        assert_eq!(req.path(), "/a b/c");
+        assert_eq!(req.raw_path(), "/a%20b/c");
+    }
+
+    fn credentials(uri: &str) -> Request {
+        Request::new(uri.to_string())
+            .headers(vec![Header::new("Authorization", "Bearer secret"), Header::new("Cookie", "a=b")])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn redirects_keep_credentials_on_the_same_origin() {
+        let (next, _) = credentials("https://example.com/a").redirected(302, url::Url::parse("https://example.com/b").unwrap());
+        assert_eq!(next.header("authorization"), Some("Bearer secret".to_string()));
+        assert_eq!(next.header("cookie"), Some("a=b".to_string()));
+    }
+
+    #[test]
+    fn redirects_drop_credentials_on_a_downgrade_to_http() {
+        let (next, _) = credentials("https://example.com/a").redirected(302, url::Url::parse("http://example.com/b").unwrap());
+        assert_eq!(next.header("authorization"), None);
+        assert_eq!(next.header("cookie"), None);
+    }
+
+    #[test]
+    fn redirects_drop_credentials_for_another_port() {
+        let (next, _) = credentials("https://example.com/a").redirected(302, url::Url::parse("https://example.com:8443/b").unwrap());
+        assert_eq!(next.header("authorization"), None);
+    }
+}
@@ -31,19 +31,31 @@ impl Response {
         response
     }
 
-    pub fn from_js(isolate: &v8::isolate::Isolate, context: &v8::context::Context, obj: &v8::value::Object) -> Response {
-        let status = obj.get(&context, &v8::value::String::from_str(&isolate, "status"));
-        let body = obj.get(&context, &v8::value::String::from_str(&isolate, "body")).into_string().unwrap().value();
+    pub fn from_js(isolate: &v8::isolate::Isolate, context: &v8::context::Context, obj: &v8::value::Object) -> Result<Response, String> {
+        let status =
+            match obj.get(&context, &v8::value::String::from_str(&isolate, "status")).into_int32() {
+                Some(s) => s.value(),
+                None => return Err("Invalid Response: status must be an integer".to_string()),
+            };
+        let body =
+            match obj.get(&context, &v8::value::String::from_str(&isolate, "body")).into_string() {
+                Some(b) => b.value(),
+                None => return Err("Invalid Response: body must be a string".to_string()),
+            };
 
         // headers
-        let arr = obj.get(&context, &v8::value::String::from_str(&isolate, "headers")).into_array().unwrap();
+        let arr =
+            match obj.get(&context, &v8::value::String::from_str(&isolate, "headers")).into_array() {
+                Some(a) => a,
+                None => return Err("Invalid Response: headers must be an array".to_string()),
+            };
         let mut headers = Vec::new();
         let mut count = 0;
         while {
             let item = arr.get(&context, &v8::value::Integer::new(&isolate, count));
             if item.is_array() { 
                 count += 1;
-                let head = Header::from_js(&isolate, &context, &item).unwrap();
+                let head = Header::from_js(&isolate, &context, &item)?;
                 headers.push(head);
                 true
             } else {
@@ -51,9 +63,9 @@ impl Response {
             }
         } {}
 
-        Response { status: status.into_int32().unwrap().value(),
-                   headers: headers,
-                   body: body }
+        Ok(Response { status: status,
+                      headers: headers,
+                      body: body })
     }
 }
 
@@ -0,0 +1,280 @@
+extern crate url;
+
+use std::cmp::Ordering;
+
+use self::url::percent_encoding::percent_decode;
+
+use models;
+use request::{self, Request};
+
+/// A single segment of a lambda path pattern.
+#[derive(Debug, PartialEq)]
+enum Segment {
+    /// Matches the segment text exactly, e.g. `users`.
+    Literal(String),
+    /// `{name}` captures exactly one segment.
+    Param(String),
+    /// `*name` captures the remainder of the path, slashes included.
+    Wildcard(String),
+}
+
+impl Segment {
+    // literal segments beat parameters, which beat wildcards
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 2,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+pub struct Match {
+    pub lambda: models::Lambda,
+    pub params: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub enum RouteError {
+    NotFound,
+    /// The path exists but none of its lambdas accept the request method;
+    /// carries the methods that would have been accepted.
+    MethodNotAllowed(Vec<String>),
+}
+
+impl Pattern {
+    pub fn parse(path: &str) -> Pattern {
+        let segments = path.split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                if s.starts_with('{') && s.ends_with('}') && s.len() > 2 {
+                    Segment::Param(s[1..s.len() - 1].to_string())
+                } else if s.starts_with('*') {
+                    Segment::Wildcard(s[1..].to_string())
+                } else {
+                    Segment::Literal(s.to_string())
+                }
+            })
+            .collect();
+        Pattern { segments: segments }
+    }
+
+    /// Matches already decoded path segments, returning the captured values.
+    pub fn matches(&self, path: &[String]) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Wildcard(name) => {
+                    // a wildcard is only meaningful as the final segment
+                    if i != self.segments.len() - 1 {
+                        return None;
+                    }
+                    params.push((name.clone(), path[i.min(path.len())..].join("/")));
+                    return Some(params);
+                },
+                Segment::Param(name) => {
+                    match path.get(i) {
+                        Some(value) => params.push((name.clone(), value.clone())),
+                        None => return None,
+                    }
+                },
+                Segment::Literal(text) => {
+                    match path.get(i) {
+                        Some(value) if value == text => {},
+                        _ => return None,
+                    }
+                },
+            }
+        }
+
+        if path.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    /// How closely the pattern fits a matching path of `len` segments,
+    /// compared segment by segment. A trailing wildcard ranks every segment
+    /// it captured, and a pattern that ends with the path beats a wildcard
+    /// that captured nothing, so `/api` wins over `/api/*rest` for `/api`.
+    fn specificity(&self, len: usize) -> Vec<u8> {
+        let mut ranks = Vec::with_capacity(len + 1);
+        for segment in self.segments.iter() {
+            if let Segment::Wildcard(_) = segment {
+                while ranks.len() < len {
+                    ranks.push(segment.rank());
+                }
+                ranks.push(0);
+                return ranks;
+            }
+            ranks.push(segment.rank());
+        }
+        ranks.push(1);
+        ranks
+    }
+}
+
+/// Splits a raw (still percent encoded) path into decoded segments.
+pub fn segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| percent_decode(s.as_bytes()).decode_utf8_lossy().to_string())
+        .collect()
+}
+
+/// The lambdas of a hostname with their patterns, parsed once when the
+/// hostname is loaded.
+pub struct Routes {
+    routes: Vec<(Pattern, models::Lambda)>,
+}
+
+impl Routes {
+    pub fn new(lambdas: Vec<models::Lambda>) -> Routes {
+        Routes {
+            routes: lambdas.into_iter().map(|l| (Pattern::parse(&l.path), l)).collect(),
+        }
+    }
+
+    /// Finds the most specific lambda whose pattern matches the request path
+    /// and accepts the request method. Lambdas bound to explicit methods win
+    /// over the `*` registration of the same pattern.
+    pub fn find(&self, request: &Request) -> Result<Match, RouteError> {
+        let path = segments(&request.raw_path());
+
+        let mut allowed: Vec<String> = Vec::new();
+        let mut best: Option<((Vec<u8>, bool), &models::Lambda, Vec<(String, String)>)> = None;
+        for &(ref pattern, ref lambda) in self.routes.iter() {
+            if let Some(params) = pattern.matches(&path) {
+                if !request::allows_method(&lambda.methods, request.method()) {
+                    for m in lambda.methods.split(',') {
+                        if !allowed.iter().any(|a| a == m) {
+                            allowed.push(m.to_string());
+                        }
+                    }
+                    continue;
+                }
+
+                let score = (pattern.specificity(path.len()), lambda.methods != request::ANY_METHOD);
+                let better =
+                    match best {
+                        Some((ref current, _, _)) => score.cmp(current) == Ordering::Greater,
+                        None => true,
+                    };
+                if better {
+                    best = Some((score, lambda, params));
+                }
+            }
+        }
+
+        match best {
+            Some((_, lambda, params)) => Ok(Match { lambda: lambda.clone(), params: params }),
+            None if !allowed.is_empty() => {
+                allowed.sort();
+                Err(RouteError::MethodNotAllowed(allowed))
+            },
+            None => Err(RouteError::NotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request::RequestBuilder;
+
+    fn lambda(id: i32, path: &str, methods: &str) -> models::Lambda {
+        models::Lambda {
+            id: id,
+            path: path.to_string(),
+            hostname: "example.com".to_string(),
+            code: String::new(),
+            version: 1,
+            sticky: None,
+            methods: methods.to_string(),
+            timeout_ms: None,
+            cpu_timeout_ms: None,
+            heap_limit_mb: None,
+            code_cache: None,
+            code_cache_tag: None,
+            max_concurrency: None,
+            rate_limit: None,
+            rate_limit_key: None,
+            egress_policy: None,
+        }
+    }
+
+    fn request(method: &str, path: &str) -> Request {
+        RequestBuilder::new(format!("http://example.com{}", path))
+            .method(method)
+            .build()
+            .unwrap()
+    }
+
+    fn found(routes: &Routes, method: &str, path: &str) -> i32 {
+        match routes.find(&request(method, path)) {
+            Ok(m) => m.lambda.id,
+            Err(e) => panic!("{} {} did not match: {:?}", method, path, e),
+        }
+    }
+
+    #[test]
+    fn exact_path_beats_wildcard() {
+        let routes = Routes::new(vec![lambda(1, "/api/*rest", "*"), lambda(2, "/api", "*")]);
+        assert_eq!(found(&routes, "GET", "/api"), 2);
+        assert_eq!(found(&routes, "GET", "/api/users"), 1);
+    }
+
+    #[test]
+    fn literal_beats_param_beats_wildcard() {
+        let routes = Routes::new(vec![
+            lambda(1, "/*rest", "*"),
+            lambda(2, "/users/*rest", "*"),
+            lambda(3, "/users/{id}", "*"),
+            lambda(4, "/users/me", "*"),
+        ]);
+        assert_eq!(found(&routes, "GET", "/users/me"), 4);
+        assert_eq!(found(&routes, "GET", "/users/42"), 3);
+        assert_eq!(found(&routes, "GET", "/users/42/posts"), 2);
+        assert_eq!(found(&routes, "GET", "/posts"), 1);
+    }
+
+    #[test]
+    fn earlier_literal_outranks_later_segments() {
+        let routes = Routes::new(vec![lambda(1, "/{kind}/latest", "*"), lambda(2, "/users/*rest", "*")]);
+        assert_eq!(found(&routes, "GET", "/users/latest"), 2);
+    }
+
+    #[test]
+    fn captures_params() {
+        let routes = Routes::new(vec![lambda(1, "/users/{id}/files/*path", "*")]);
+        let m = routes.find(&request("GET", "/users/a%20b/files/x/y.txt")).unwrap();
+        assert_eq!(m.params, vec![("id".to_string(), "a b".to_string()),
+                                  ("path".to_string(), "x/y.txt".to_string())]);
+    }
+
+    #[test]
+    fn method_bound_lambda_beats_any_method() {
+        let routes = Routes::new(vec![lambda(1, "/items", "*"), lambda(2, "/items", "POST")]);
+        assert_eq!(found(&routes, "POST", "/items"), 2);
+        assert_eq!(found(&routes, "GET", "/items"), 1);
+    }
+
+    #[test]
+    fn reports_allowed_methods() {
+        let routes = Routes::new(vec![lambda(1, "/items", "PUT,POST"), lambda(2, "/items/{id}", "GET")]);
+        match routes.find(&request("GET", "/items")) {
+            Err(RouteError::MethodNotAllowed(methods)) => assert_eq!(methods, vec!["POST", "PUT"]),
+            _ => panic!("expected MethodNotAllowed"),
+        }
+        match routes.find(&request("GET", "/other")) {
+            Err(RouteError::NotFound) => {},
+            _ => panic!("expected NotFound"),
+        }
+    }
+}
@@ -0,0 +1,664 @@
+extern crate v8;
+extern crate libc;
+extern crate sha2;
+
+use std::fmt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::sha2::{Digest, Sha256};
+
+use egress::Egress;
+use error::Error;
+use fetch::{EventLoop, Fetched};
+use functions;
+use metrics::Metrics;
+use outbound::HttpClients;
+use models;
+use native::{self, CompiledScript, HeapLimit, IsolateHandle};
+use request::Request;
+use response::Response;
+
+/// Why a lambda's source was rejected, returned as the body of a `400`.
+#[derive(Debug, Serialize)]
+pub struct CompileError {
+    pub error: String,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+impl CompileError {
+    fn new(error: &str) -> CompileError {
+        CompileError { error: error.to_string(), line: None, column: None }
+    }
+
+    fn from_v8(e: v8::error::Error) -> CompileError {
+        match *e.kind() {
+            v8::error::ErrorKind::Javascript(ref message, ref stack_trace) => {
+                let frame = stack_trace.frames.first();
+                CompileError {
+                    error: message.clone(),
+                    line: frame.map(|f| f.line),
+                    column: frame.map(|f| f.column),
+                }
+            },
+            _ => CompileError::new(&e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{} ({}:{})", self.error, line, column),
+            _ => write!(f, "{}", self.error),
+        }
+    }
+}
+
+/// V8's compiled form of a lambda's code, stored with each version so an
+/// isolate does not have to parse the source again.
+#[derive(Clone, Debug)]
+pub struct CodeCache {
+    pub data: Vec<u8>,
+    /// `tag(code)` of the source it was produced from.
+    pub tag: String,
+}
+
+impl CodeCache {
+    /// The cache stored with a version, if it has one.
+    pub fn stored(data: Option<Vec<u8>>, tag: Option<String>) -> Option<CodeCache> {
+        match (data, tag) {
+            (Some(d), Some(t)) => Some(CodeCache { data: d, tag: t }),
+            _ => None,
+        }
+    }
+
+    /// A cache is only valid for the source and the V8 build that produced it.
+    fn tag(code: &str) -> String {
+        let hash: String = Sha256::digest(code.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("{}:{}", native::v8_version(), hash)
+    }
+
+    fn matches(&self, code: &str) -> bool {
+        self.tag == CodeCache::tag(code)
+    }
+}
+
+/// Compiles `code` in a throwaway isolate and checks that it defines a
+/// `handler` function, so broken code is rejected at deploy time instead of
+/// failing every request. Top level code runs under the same `limits` as an
+/// invocation. Returns the code cache to store with the version, `None` when
+/// V8 produced none.
+fn validate(code: &str, limits: Limits, watchdog: &Watchdog) -> Result<Option<CodeCache>, CompileError> {
+    let mut instance = Instance::new(limits);
+    let armed = watchdog.arm(&instance, limits);
+
+    let result = instance.load(code, None)
+        .map_err(|e| instance.compile_error(e, code))
+        .and_then(|_| match instance.handler() {
+            // created after the top level code ran, so the functions it
+            // compiled lazily are included
+            Some(_) => Ok(instance.code_cache().map(|data| CodeCache { data: data, tag: CodeCache::tag(code) })),
+            None => Err(CompileError::new("handler is not defined as a function")),
+        });
+
+    match armed.disarm() {
+        Some(exceeded) => Err(CompileError::new(&exceeded.to_string())),
+        None if instance.heap.exceeded() => Err(CompileError::new(&heap_limit_message(limits))),
+        None => result,
+    }
+}
+
+/// How often the watchdog checks a running invocation against its limits.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs before a lambda's code, defines `fetch()` and `http.request`.
+const PRELUDE: &str = include_str!("prelude.js");
+
+/// How often a pending promise returned by a lambda is checked again.
+const SETTLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Records the outcome of a promise on the object it returns.
+const SETTLE: &str = "(function (promise) {
+  var state = { done: false, rejected: false };
+  promise.then(
+    function (value) { state.done = true; state.value = value; },
+    function (error) { state.done = true; state.rejected = true; state.value = error; });
+  return state;
+})";
+
+/// How long a single invocation may run and how much memory it may use.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Wall-clock time, including time spent waiting on `http.request`.
+    pub timeout: Duration,
+    /// CPU time used by the thread running the lambda.
+    pub cpu_time: Duration,
+    /// Heap of the isolate in megabytes.
+    pub heap_mb: usize,
+}
+
+impl Limits {
+    /// These limits overridden by the ones set on `lambda`.
+    pub fn for_lambda(&self, lambda: &models::Lambda) -> Limits {
+        let ms = |limit: Option<i32>, default: Duration| {
+            limit.map(|ms| Duration::from_millis(ms as u64)).unwrap_or(default)
+        };
+        Limits {
+            timeout: ms(lambda.timeout_ms, self.timeout),
+            cpu_time: ms(lambda.cpu_timeout_ms, self.cpu_time),
+            heap_mb: lambda.heap_limit_mb.map(|mb| mb as usize).unwrap_or(self.heap_mb),
+        }
+    }
+
+    fn heap_bytes(&self) -> usize {
+        self.heap_mb * 1024 * 1024
+    }
+}
+
+fn heap_limit_message(limits: Limits) -> String {
+    format!("Execution exceeded the {}MB heap limit", limits.heap_mb)
+}
+
+/// An isolate kept warm between invocations of the same lambda version.
+/// Every invocation gets a new context, so no global state of one call is
+/// seen by the next. The warm isolate spares building another heap and
+/// compiling the prelude and the lambda's code again, their top level code
+/// still runs in every new context.
+struct Instance {
+    isolate: v8::Isolate,
+    context: v8::Context,
+    /// The prelude and the lambda's code, compiled by the first `load`.
+    compiled: Option<(CompiledScript, CompiledScript)>,
+    /// Terminates the script when it uses more heap than it may.
+    heap: HeapLimit,
+    /// Set when the watchdog terminated the script.
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Instance {
+    fn new(limits: Limits) -> Instance {
+        let isolate = v8::Isolate::new();
+        let context = v8::Context::new(&isolate);
+        let heap = HeapLimit::new(&isolate, limits.heap_bytes());
+        Instance {
+            isolate: isolate,
+            context: context,
+            compiled: None,
+            heap: heap,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Replaces the context with an empty one for the next invocation,
+    /// which runs under `limits`.
+    fn fresh_context(&mut self, limits: Limits) {
+        self.context = v8::Context::new(&self.isolate);
+        self.heap.set(limits.heap_bytes());
+    }
+
+    /// Whether the script was terminated, by the watchdog or for its heap.
+    fn interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst) || self.heap.exceeded()
+    }
+
+    /// Lets `fetch()` queue requests on `events`.
+    fn bind(&self, events: &EventLoop) {
+        let queue = events.queue();
+        let function = v8::value::Function::new(&self.isolate, &self.context, 1,
+                                                Box::new(move |info: v8::value::FunctionCallbackInfo| functions::queue_fetch(&queue, info)));
+        self.context.global().set(&self.context, &v8::value::String::from_str(&self.isolate, "__fetch"), &function);
+    }
+
+    /// Hands the outcome of a request started by `fetch()` to the script.
+    fn deliver(&self, id: i32, result: Result<Fetched, String>) -> Result<(), Error> {
+        let isolate = &self.isolate;
+        let context = &self.context;
+        let done =
+            match context.global().get(context, &v8::value::String::from_str(isolate, "__fetchDone")).into_function() {
+                Some(f) => f,
+                None => return Err(Error::Runtime("__fetchDone is not defined as a function".to_string())),
+            };
+
+        let id = v8::value::Value::from(v8::value::Integer::new(isolate, id));
+        let null = || v8::value::Value::from(v8::value::null(isolate));
+        let args =
+            match result {
+                Ok(fetched) => vec![id, null(), v8::value::Value::from(fetched.js(isolate, context))],
+                Err(e) => vec![id, v8::value::Value::from(v8::value::String::from_str(isolate, &e)), null()],
+            };
+        let args: Vec<&v8::value::Value> = args.iter().collect();
+        done.call(context, &args).map_err(runtime_error)?;
+        Ok(())
+    }
+
+    /// Waits for `value` to settle when it is a promise, running `events` in
+    /// the meantime, and returns what it resolved to. A rejection is a
+    /// runtime error. Promise callbacks run whenever a call into the isolate
+    /// returns, a promise that is still pending waits on `fetch()` or until
+    /// the watchdog stops the invocation.
+    fn settle(&self, value: v8::value::Value, events: &mut EventLoop) -> Result<v8::value::Value, Error> {
+        if !value.is_promise() {
+            return Ok(value);
+        }
+
+        let isolate = &self.isolate;
+        let context = &self.context;
+        let settle =
+            v8::Script::compile(isolate, context, &v8::value::String::from_str(isolate, SETTLE))
+                .and_then(|script| script.run(context))
+                .map_err(runtime_error)?
+                .into_function()
+                .ok_or_else(|| Error::Internal("Unable to await the handler's promise".to_string()))?;
+        let state = settle.call(context, &[&value]).map_err(runtime_error)?
+            .into_object()
+            .ok_or_else(|| Error::Internal("Unable to await the handler's promise".to_string()))?;
+
+        let key = |name: &str| v8::value::String::from_str(isolate, name);
+        while !state.get(context, &key("done")).is_true() {
+            // `run` reports which limit was hit
+            if self.interrupted() {
+                return Err(Error::Runtime("Execution terminated while awaiting a promise".to_string()));
+            }
+            isolate.run_enqueued_tasks();
+            for (id, result) in events.turn(SETTLE_INTERVAL) {
+                self.deliver(id, result)?;
+            }
+        }
+
+        let value = state.get(context, &key("value"));
+        if state.get(context, &key("rejected")).is_true() {
+            return Err(Error::Runtime(format!("Uncaught (in promise) {}", value.to_string(context).value())));
+        }
+        Ok(value)
+    }
+
+    /// Runs the prelude's and the lambda's top level code in the current
+    /// context, compiling them first unless an earlier invocation did. The
+    /// code is compiled from `cache` when it was produced for this code by
+    /// this V8 build.
+    fn load(&mut self, code: &str, cache: Option<&CodeCache>) -> Result<(), v8::error::Error> {
+        let _guard = self.context.make_current();
+        if self.compiled.is_none() {
+            let prelude = CompiledScript::compile(&self.isolate, PRELUDE)?;
+            let cache = cache.filter(|c| c.matches(code)).map(|c| c.data.as_slice());
+            let (script, rejected) = CompiledScript::compile_with_cache(&self.isolate, code, cache)?;
+            if rejected {
+                debug!("V8 rejected the code cache, compiled from source");
+            }
+            self.compiled = Some((prelude, script));
+        }
+        if let Some((ref prelude, ref script)) = self.compiled {
+            prelude.run()?;
+            script.run()?;
+        }
+        Ok(())
+    }
+
+    /// V8's code cache for the lambda's code, once `load` compiled it.
+    fn code_cache(&self) -> Option<Vec<u8>> {
+        self.compiled.as_ref().and_then(|&(_, ref script)| script.code_cache())
+    }
+
+    /// `e` raised while loading `code`. A syntax error has no stack frames,
+    /// its position is read from V8's message instead.
+    fn compile_error(&self, e: v8::error::Error, code: &str) -> CompileError {
+        let mut error = CompileError::from_v8(e);
+        if error.line.is_none() {
+            let _guard = self.context.make_current();
+            if let Some((line, column)) = native::syntax_error_position(&self.isolate, code) {
+                error.line = Some(line);
+                error.column = Some(column);
+            }
+        }
+        error
+    }
+
+    fn handler(&self) -> Option<v8::value::Function> {
+        self.context.global()
+            .get(&self.context, &v8::value::String::from_str(&self.isolate, "handler"))
+            .into_function()
+    }
+
+    /// Calls the optional `rateLimitKey` function with `request`, its result
+    /// identifies the client for rate limiting.
+    fn rate_limit_key(&self, request: &Request, events: &mut EventLoop) -> Result<Option<String>, Error> {
+        let function =
+            self.context.global()
+                .get(&self.context, &v8::value::String::from_str(&self.isolate, "rateLimitKey"))
+                .into_function();
+        let function =
+            match function {
+                Some(f) => f,
+                None => return Ok(None),
+            };
+
+        self.bind(events);
+        let js_request = request.js(&self.isolate, &self.context);
+        let key = function.call(&self.context, &[&js_request]).map_err(runtime_error)?;
+        let key = self.settle(key, events)?;
+        Ok(Some(key.to_string(&self.context).value()))
+    }
+
+    /// Calls `handler` with `request`, setting the `http` and `response`
+    /// globals it uses.
+    fn invoke(&self, request: &Request, events: &mut EventLoop) -> Result<Response, Error> {
+        let isolate = &self.isolate;
+        let context = &self.context;
+
+        let js_request = request.js(isolate, context);
+        // initial response object
+        let js_response = Response::new().js(isolate, context);
+
+        let global = context.global();
+        // helper functions
+        let http = v8::value::Object::new(isolate, context);
+        let blocking = events.blocking();
+        let deadline = events.deadline();
+        let http_request = v8::value::Function::new(isolate, context, 1,
+                                                    Box::new(move |info: v8::value::FunctionCallbackInfo| functions::make_request(&blocking, deadline, info)));
+        global.set(context, &v8::value::String::from_str(isolate, "__http"), &http_request);
+        // the prelude's wrapper encodes the body and builds the response
+        let wrapper = global.get(context, &v8::value::String::from_str(isolate, "__httpRequest"));
+        http.set(context, &v8::value::String::from_str(isolate, "request"), &wrapper);
+        global.set(context, &v8::value::String::from_str(isolate, "http"), &http);
+        self.bind(events);
+
+        // set default response values
+        global.set(context, &v8::value::String::from_str(isolate, "response"), &js_response);
+
+        // endpoint
+        let handler =
+            match self.handler() {
+                Some(f) => f,
+                None => return Err(Error::Runtime("handler is not defined as a function".to_string())),
+            };
+        let result = handler.call(context, &[&js_request]).map_err(runtime_error)?;
+        // an async handler may still change `response` before it resolves
+        let result = self.settle(result, events)?;
+        let body = result.to_string(context).value();
+
+        let response =
+            match global.get(context, &v8::value::String::from_str(isolate, "response")).into_object() {
+                Some(r) => r,
+                None => return Err(Error::Bridge("Invalid Response: response must be an object".to_string())),
+            };
+        let mut response = Response::from_js(isolate, context, &response).map_err(Error::Bridge)?;
+        response.body = body;
+
+        Ok(response)
+    }
+}
+
+/// Which limit made the watchdog terminate an invocation.
+#[derive(Clone, Copy, Debug)]
+enum Exceeded {
+    Timeout(Duration),
+    CpuTime(Duration),
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64;
+        match *self {
+            Exceeded::Timeout(d) => write!(f, "Execution exceeded the {}ms time limit", ms(d)),
+            Exceeded::CpuTime(d) => write!(f, "Execution exceeded the {}ms CPU time limit", ms(d)),
+        }
+    }
+}
+
+extern "C" {
+    // not bound by the libc crate
+    fn pthread_getcpuclockid(thread: libc::pthread_t, clock: *mut libc::clockid_t) -> libc::c_int;
+}
+
+/// The CPU time clock of the calling thread.
+fn thread_cpu_clock() -> Option<libc::clockid_t> {
+    let mut clock: libc::clockid_t = 0;
+    match unsafe { pthread_getcpuclockid(libc::pthread_self(), &mut clock) } {
+        0 => Some(clock),
+        _ => None,
+    }
+}
+
+fn cpu_time(clock: libc::clockid_t) -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// An invocation the watchdog is timing.
+struct Deadline {
+    isolate: IsolateHandle,
+    limits: Limits,
+    started: Instant,
+    /// CPU clock of the thread running the invocation and its reading when
+    /// the invocation started.
+    cpu: Option<(libc::clockid_t, Duration)>,
+    interrupted: Arc<AtomicBool>,
+    exceeded: Option<Exceeded>,
+}
+
+impl Deadline {
+    /// The limit the invocation ran past so far, if any.
+    fn overrun(&self) -> Option<Exceeded> {
+        let cpu_used =
+            match self.cpu {
+                Some((clock, started)) => cpu_time(clock) - started,
+                None => Duration::from_secs(0),
+            };
+        if self.started.elapsed() >= self.limits.timeout {
+            Some(Exceeded::Timeout(self.limits.timeout))
+        } else if cpu_used >= self.limits.cpu_time {
+            Some(Exceeded::CpuTime(self.limits.cpu_time))
+        } else {
+            None
+        }
+    }
+}
+
+/// Terminates invocations that exceed their wall-clock or CPU time limit.
+/// One thread, started with the server, checks every armed invocation.
+pub struct Watchdog {
+    armed: Mutex<HashMap<u64, Deadline>>,
+    next: AtomicUsize,
+}
+
+impl Watchdog {
+    pub fn start() -> Result<Arc<Watchdog>, String> {
+        let watchdog = Arc::new(Watchdog { armed: Mutex::new(HashMap::new()), next: AtomicUsize::new(0) });
+        let checked = watchdog.clone();
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || loop {
+                thread::sleep(WATCHDOG_INTERVAL);
+                checked.check();
+            })
+            .map_err(|e| format!("Unable to start watchdog: {}", e))?;
+        Ok(watchdog)
+    }
+
+    fn check(&self) {
+        let mut armed = self.armed.lock().unwrap_or_else(|e| e.into_inner());
+        for deadline in armed.values_mut().filter(|d| d.exceeded.is_none()) {
+            deadline.exceeded = deadline.overrun();
+            if deadline.exceeded.is_some() {
+                deadline.interrupted.store(true, Ordering::SeqCst);
+                // an armed isolate is alive, `disarm` takes this lock before
+                // its instance can be dropped
+                unsafe { deadline.isolate.terminate_execution() };
+            }
+        }
+    }
+
+    /// Starts timing `instance` against `limits`. Must be called on the
+    /// thread that runs the instance.
+    fn arm(&self, instance: &Instance, limits: Limits) -> Armed {
+        let id = self.next.fetch_add(1, Ordering::Relaxed) as u64;
+        let deadline = Deadline {
+            isolate: IsolateHandle::new(&instance.isolate),
+            limits: limits,
+            started: Instant::now(),
+            cpu: thread_cpu_clock().map(|clock| (clock, cpu_time(clock))),
+            interrupted: instance.interrupted.clone(),
+            exceeded: None,
+        };
+        self.armed.lock().unwrap_or_else(|e| e.into_inner()).insert(id, deadline);
+        Armed { watchdog: self, id: id }
+    }
+
+    fn remove(&self, id: u64) -> Option<Deadline> {
+        self.armed.lock().unwrap_or_else(|e| e.into_inner()).remove(&id)
+    }
+}
+
+/// An armed invocation, disarmed at the latest when dropped so the watchdog
+/// never touches an isolate after it is gone.
+struct Armed<'a> {
+    watchdog: &'a Watchdog,
+    id: u64,
+}
+
+impl<'a> Armed<'a> {
+    /// Stops timing, reporting the limit that was exceeded, if any. An
+    /// instance that was terminated must not be used again.
+    fn disarm(self) -> Option<Exceeded> {
+        self.watchdog.remove(self.id).and_then(|d| d.exceeded)
+    }
+}
+
+impl<'a> Drop for Armed<'a> {
+    fn drop(&mut self) {
+        self.watchdog.remove(self.id);
+    }
+}
+
+/// A deployed lambda version to run.
+pub struct Script<'a> {
+    pub lambda_id: i32,
+    pub version: i32,
+    pub code: &'a str,
+    pub cache: Option<&'a CodeCache>,
+    /// Where its outbound requests may go.
+    pub egress: &'a Egress,
+}
+
+/// The lambda version a warm instance runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Key {
+    lambda_id: i32,
+    version: i32,
+}
+
+/// Warm instances owned by one executor thread, isolates can not move
+/// between threads. The least recently used instance is evicted once more
+/// than `capacity` are kept.
+pub struct IsolatePool {
+    capacity: usize,
+    /// Ordered from least to most recently used.
+    warm: Vec<(Key, Instance)>,
+    /// Drives the requests started by `fetch()`.
+    events: EventLoop,
+    metrics: Arc<Metrics>,
+    watchdog: Arc<Watchdog>,
+}
+
+impl IsolatePool {
+    pub fn new(capacity: usize, metrics: Arc<Metrics>, http: Arc<HttpClients>, watchdog: Arc<Watchdog>) -> Result<IsolatePool, Error> {
+        let events = EventLoop::new(http).map_err(Error::Internal)?;
+        Ok(IsolatePool { capacity: capacity, warm: Vec::new(), events: events, metrics: metrics, watchdog: watchdog })
+    }
+
+    fn take(&mut self, key: Key) -> Option<Instance> {
+        let index = self.warm.iter().position(|&(k, _)| k == key)?;
+        self.metrics.warm_isolates.fetch_sub(1, Ordering::Relaxed);
+        Some(self.warm.remove(index).1)
+    }
+
+    fn put(&mut self, key: Key, instance: Instance) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.warm.len() >= self.capacity {
+            self.warm.remove(0);
+            self.metrics.warm_isolates.fetch_sub(1, Ordering::Relaxed);
+            self.metrics.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        self.warm.push((key, instance));
+        self.metrics.warm_isolates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Checks code about to be deployed in a throwaway isolate, see
+    /// `validate`.
+    pub fn validate(&self, code: &str, limits: Limits) -> Result<Option<CodeCache>, CompileError> {
+        validate(code, limits, &self.watchdog)
+    }
+
+    /// Runs `script`'s handler against `request`. The handler's return value
+    /// becomes the body, `response.status` and `response.headers` the rest.
+    pub fn execute(&mut self, script: &Script, request: &Request, limits: Limits) -> Result<Response, Error> {
+        self.run(script, limits, |instance, events| instance.invoke(request, events))
+    }
+
+    /// Calls `script`'s `rateLimitKey(request)`, `None` when it does not
+    /// define one.
+    pub fn rate_limit_key(&mut self, script: &Script, request: &Request, limits: Limits) -> Result<Option<String>, Error> {
+        self.run(script, limits, |instance, events| instance.rate_limit_key(request, events))
+    }
+
+    /// Calls into `script` in a warm instance when there is one. Calls
+    /// running past `limits` are terminated and fail with a timeout or heap
+    /// limit error, their instance is thrown away.
+    fn run<T, F>(&mut self, script: &Script, limits: Limits, call: F) -> Result<T, Error>
+        where F: FnOnce(&Instance, &mut EventLoop) -> Result<T, Error>
+    {
+        let key = Key { lambda_id: script.lambda_id, version: script.version };
+        let (mut instance, cold) =
+            match self.take(key) {
+                Some(i) => (i, false),
+                None => (Instance::new(limits), true),
+            };
+        if cold {
+            self.metrics.cold_starts.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.metrics.warm_starts.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if !cold {
+            instance.fresh_context(limits);
+        }
+        self.events.reset(script.egress.clone(), Instant::now() + limits.timeout);
+        let watchdog = self.watchdog.clone();
+        let armed = watchdog.arm(&instance, limits);
+
+        // stored code was validated on deploy, failing here is still a runtime
+        // error of the lambda and not of the caller's request
+        let loaded = instance.load(script.code, script.cache).map_err(runtime_error);
+        let result = {
+            let events = &mut self.events;
+            loaded.and_then(|_| call(&instance, events))
+        };
+
+        match armed.disarm() {
+            Some(exceeded) => Err(Error::Timeout(exceeded.to_string())),
+            None if instance.heap.exceeded() => Err(Error::HeapLimit(heap_limit_message(limits))),
+            None => {
+                if result.is_ok() {
+                    self.put(key, instance);
+                }
+                result
+            },
+        }
+    }
+}
+
+fn runtime_error(e: v8::error::Error) -> Error {
+    Error::Runtime(CompileError::from_v8(e).to_string())
+}
@@ -4,5 +4,87 @@ table! {
         path -> Text,
         hostname -> Text,
         code -> Text,
+        version -> Int4,
+        sticky -> Nullable<Text>,
+        methods -> Text,
+        timeout_ms -> Nullable<Int4>,
+        cpu_timeout_ms -> Nullable<Int4>,
+        heap_limit_mb -> Nullable<Int4>,
+        code_cache -> Nullable<Binary>,
+        code_cache_tag -> Nullable<Text>,
+        max_concurrency -> Nullable<Int4>,
+        rate_limit -> Nullable<Int4>,
+        rate_limit_key -> Nullable<Text>,
+        egress_policy -> Nullable<Text>,
     }
 }
+
+table! {
+    lambda_versions (id) {
+        id -> Int4,
+        lambda_id -> Int4,
+        version -> Int4,
+        code -> Text,
+        created_at -> Int8,
+        code_cache -> Nullable<Binary>,
+        code_cache_tag -> Nullable<Text>,
+    }
+}
+
+table! {
+    lambda_aliases (id) {
+        id -> Int4,
+        lambda_id -> Int4,
+        name -> Text,
+        version -> Int4,
+    }
+}
+
+table! {
+    lambda_traffic (id) {
+        id -> Int4,
+        lambda_id -> Int4,
+        version -> Int4,
+        weight -> Int4,
+    }
+}
+
+table! {
+    api_keys (id) {
+        id -> Int4,
+        name -> Text,
+        key_hash -> Text,
+        hostnames -> Text,
+        created_at -> Int8,
+    }
+}
+
+table! {
+    host_limits (hostname) {
+        hostname -> Text,
+        max_concurrency -> Nullable<Int4>,
+        rate_limit -> Nullable<Int4>,
+        rate_limit_key -> Nullable<Text>,
+    }
+}
+
+table! {
+    host_egress (hostname) {
+        hostname -> Text,
+        policy -> Text,
+    }
+}
+
+joinable!(lambda_versions -> lambdas (lambda_id));
+joinable!(lambda_aliases -> lambdas (lambda_id));
+joinable!(lambda_traffic -> lambdas (lambda_id));
+
+allow_tables_to_appear_in_same_query!(
+    lambdas,
+    lambda_versions,
+    lambda_aliases,
+    lambda_traffic,
+    api_keys,
+    host_limits,
+    host_egress,
+);
@@ -0,0 +1,592 @@
+extern crate r2d2;
+extern crate diesel_migrations;
+
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+
+use diesel;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use diesel::sqlite::SqliteConnection;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
+use diesel::result::DatabaseErrorKind;
+use diesel::sql_types::Text;
+use self::diesel_migrations::MigrationConnection;
+
+use cache;
+use migrate;
+use models;
+use schema;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Pool(r2d2::Error),
+    Query(diesel::result::Error),
+    /// A write lost against a concurrent one, e.g. two deploys of the same
+    /// lambda picking the same version number.
+    Conflict(diesel::result::Error),
+    Migration(diesel_migrations::RunMigrationsError),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Pool(e) => write!(f, "connection pool error: {}", e),
+            StorageError::Query(e) => write!(f, "query error: {}", e),
+            StorageError::Conflict(e) => write!(f, "conflicting write: {}", e),
+            StorageError::Migration(e) => write!(f, "migration error: {}", e),
+        }
+    }
+}
+
+impl error::Error for StorageError {
+    fn description(&self) -> &str {
+        match self {
+            StorageError::Pool(_) => "connection pool error",
+            StorageError::Query(_) => "query error",
+            StorageError::Conflict(_) => "conflicting write",
+            StorageError::Migration(_) => "migration error",
+        }
+    }
+}
+
+impl From<r2d2::Error> for StorageError {
+    fn from(e: r2d2::Error) -> StorageError {
+        StorageError::Pool(e)
+    }
+}
+
+impl From<diesel::result::Error> for StorageError {
+    fn from(e: diesel::result::Error) -> StorageError {
+        match e {
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => StorageError::Conflict(e),
+            e => StorageError::Query(e),
+        }
+    }
+}
+
+impl From<diesel_migrations::RunMigrationsError> for StorageError {
+    fn from(e: diesel_migrations::RunMigrationsError) -> StorageError {
+        StorageError::Migration(e)
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// Persistence used by `DbExecutor`. Implementations only store and load
+/// records, the rules about what may be stored live in the executor.
+pub trait Storage: Send + Sync {
+    /// The newest migration applied to the database, if any.
+    fn schema_version(&self) -> StorageResult<Option<String>>;
+    /// Applies the migrations embedded for this backend that have not run yet.
+    fn migrate(&self) -> StorageResult<()>;
+
+    fn lambdas(&self, hostname: Option<&str>) -> StorageResult<Vec<models::Lambda>>;
+    fn find_lambda(&self, path: &str, hostname: &str, methods: &str) -> StorageResult<Option<models::Lambda>>;
+    /// Stores `code` as a new version and makes it the active one, creating
+    /// the lambda if needed.
+    fn deploy(&self, lambda: &models::NewLambda, created_at: i64) -> StorageResult<models::Lambda>;
+    fn delete_lambda(&self, id: i32) -> StorageResult<()>;
+
+    fn versions(&self, lambda_id: i32) -> StorageResult<Vec<models::LambdaVersion>>;
+    fn find_version(&self, lambda_id: i32, version: i32) -> StorageResult<Option<models::LambdaVersion>>;
+    fn activate(&self, lambda_id: i32, version: &models::LambdaVersion) -> StorageResult<models::Lambda>;
+
+    fn aliases(&self, lambda_id: i32) -> StorageResult<Vec<models::LambdaAlias>>;
+    fn find_alias(&self, lambda_id: i32, name: &str) -> StorageResult<Option<models::LambdaAlias>>;
+    fn set_alias(&self, alias: &models::NewLambdaAlias) -> StorageResult<models::LambdaAlias>;
+    fn delete_alias(&self, id: i32) -> StorageResult<()>;
+
+    fn traffic(&self, lambda_id: i32) -> StorageResult<Vec<models::LambdaTraffic>>;
+    /// Replaces the whole traffic configuration of a lambda.
+    fn set_traffic(&self, lambda_id: i32, sticky: Option<&str>, routes: &[models::NewLambdaTraffic]) -> StorageResult<models::Lambda>;
+    fn set_limits(&self, lambda_id: i32, limits: &models::LambdaLimits) -> StorageResult<models::Lambda>;
+    fn host_limits(&self, hostname: &str) -> StorageResult<Option<models::HostLimits>>;
+    fn set_host_limits(&self, limits: &models::HostLimits) -> StorageResult<models::HostLimits>;
+    /// Stores a lambda's egress policy, `None` removes it.
+    fn set_egress(&self, lambda_id: i32, policy: Option<&str>) -> StorageResult<models::Lambda>;
+    fn host_egress(&self, hostname: &str) -> StorageResult<Option<models::HostEgress>>;
+    /// Stores a hostname's egress policy, `None` removes it.
+    fn set_host_egress(&self, hostname: &str, policy: Option<&str>) -> StorageResult<Option<models::HostEgress>>;
+    /// Tells other server instances that lambdas of `hostname` changed.
+    fn notify_change(&self, hostname: &str) -> StorageResult<()>;
+
+    fn api_keys(&self) -> StorageResult<Vec<models::ApiKey>>;
+    fn find_api_key(&self, key_hash: &str) -> StorageResult<Option<models::ApiKey>>;
+    fn create_api_key(&self, key: &models::NewApiKey) -> StorageResult<models::ApiKey>;
+    fn delete_api_key(&self, id: i32) -> StorageResult<()>;
+}
+
+/// How a backend announces changes to other server instances, see
+/// `cache::watch` for the receiving end.
+trait NotifyChange {
+    fn notify_change(&self, hostname: &str) -> diesel::QueryResult<()>;
+}
+
+impl NotifyChange for PgConnection {
+    fn notify_change(&self, hostname: &str) -> diesel::QueryResult<()> {
+        diesel::sql_query("SELECT pg_notify($1, $2)")
+            .bind::<Text, _>(cache::CHANGES_CHANNEL)
+            .bind::<Text, _>(hostname)
+            .execute(self)
+            .map(|_| ())
+    }
+}
+
+impl NotifyChange for SqliteConnection {
+    fn notify_change(&self, _: &str) -> diesel::QueryResult<()> {
+        // other instances poll the database file for writes
+        Ok(())
+    }
+}
+
+/// Runs `f` in a transaction that may write. SQLite takes the write lock up
+/// front, a deferred transaction upgrading its read lock would fail with
+/// `SQLITE_BUSY` without waiting for `busy_timeout` when two writers race.
+trait WriteTransaction {
+    fn write_transaction<T, F>(&self, f: F) -> diesel::QueryResult<T>
+        where F: FnOnce() -> diesel::QueryResult<T>;
+}
+
+impl WriteTransaction for PgConnection {
+    fn write_transaction<T, F>(&self, f: F) -> diesel::QueryResult<T>
+        where F: FnOnce() -> diesel::QueryResult<T>
+    {
+        self.transaction(f)
+    }
+}
+
+impl WriteTransaction for SqliteConnection {
+    fn write_transaction<T, F>(&self, f: F) -> diesel::QueryResult<T>
+        where F: FnOnce() -> diesel::QueryResult<T>
+    {
+        self.immediate_transaction(f)
+    }
+}
+
+// Both backends share the same queries. Only statements every backend
+// understands are used here: no RETURNING and no ON CONFLICT, rows are read
+// back after they are written.
+macro_rules! diesel_storage {
+    ($name:ident, $conn:ty, $migrations:ident) => {
+        pub struct $name(pub Pool<ConnectionManager<$conn>>);
+
+        impl Storage for $name {
+            fn schema_version(&self) -> StorageResult<Option<String>> {
+                let conn = self.0.get()?;
+
+                diesel_migrations::setup_database(&*conn)?;
+                Ok(conn.latest_run_migration_version()?)
+            }
+
+            fn migrate(&self) -> StorageResult<()> {
+                let conn = self.0.get()?;
+
+                Ok(migrate::$migrations::run(&*conn)?)
+            }
+
+            fn lambdas(&self, hostname: Option<&str>) -> StorageResult<Vec<models::Lambda>> {
+                use self::schema::lambdas;
+
+                let conn = self.0.get()?;
+
+                let mut query = lambdas::table.into_boxed();
+                if let Some(h) = hostname {
+                    query = query.filter(lambdas::hostname.eq(h));
+                }
+
+                Ok(query
+                    .order((lambdas::hostname.asc(), lambdas::path.asc(), lambdas::methods.asc()))
+                    .load::<models::Lambda>(&*conn)?)
+            }
+
+            fn find_lambda(&self, path: &str, hostname: &str, methods: &str) -> StorageResult<Option<models::Lambda>> {
+                use self::schema::lambdas;
+
+                let conn = self.0.get()?;
+
+                Ok(lambdas::table
+                    .filter(lambdas::path.eq(path))
+                    .filter(lambdas::hostname.eq(hostname))
+                    .filter(lambdas::methods.eq(methods))
+                    .first::<models::Lambda>(&*conn)
+                    .optional()?)
+            }
+
+            fn deploy(&self, lambda: &models::NewLambda, created_at: i64) -> StorageResult<models::Lambda> {
+                use self::schema::{lambdas, lambda_versions};
+
+                let conn = self.0.get()?;
+                let conn: &$conn = &*conn;
+
+                let find = || lambdas::table
+                    .filter(lambdas::path.eq(lambda.path))
+                    .filter(lambdas::hostname.eq(lambda.hostname))
+                    .filter(lambdas::methods.eq(lambda.methods))
+                    .first::<models::Lambda>(conn);
+
+                Ok(conn.write_transaction(|| {
+                    let existing = match find().optional()? {
+                        Some(l) => l,
+                        None => {
+                            diesel::insert_into(lambdas::table).values(lambda).execute(conn)?;
+                            find()?
+                        },
+                    };
+
+                    // every deploy becomes a new version, the lambda points at the latest
+                    let latest: Option<i32> = lambda_versions::table
+                        .filter(lambda_versions::lambda_id.eq(existing.id))
+                        .select(diesel::dsl::max(lambda_versions::version))
+                        .first(conn)?;
+                    let next = latest.unwrap_or(0) + 1;
+
+                    diesel::insert_into(lambda_versions::table)
+                        .values(&models::NewLambdaVersion {
+                            lambda_id: existing.id,
+                            version: next,
+                            code: lambda.code,
+                            created_at: created_at,
+                            code_cache: lambda.code_cache,
+                            code_cache_tag: lambda.code_cache_tag,
+                        })
+                        .execute(conn)?;
+
+                    diesel::update(lambdas::table.find(existing.id))
+                        .set((lambdas::code.eq(lambda.code),
+                              lambdas::version.eq(next),
+                              lambdas::code_cache.eq(lambda.code_cache),
+                              lambdas::code_cache_tag.eq(lambda.code_cache_tag)))
+                        .execute(conn)?;
+
+                    find()
+                })?)
+            }
+
+            fn delete_lambda(&self, id: i32) -> StorageResult<()> {
+                use self::schema::lambdas;
+
+                let conn = self.0.get()?;
+
+                diesel::delete(lambdas::table.find(id)).execute(&*conn)?;
+                Ok(())
+            }
+
+            fn versions(&self, lambda_id: i32) -> StorageResult<Vec<models::LambdaVersion>> {
+                use self::schema::lambda_versions;
+
+                let conn = self.0.get()?;
+
+                Ok(lambda_versions::table
+                    .filter(lambda_versions::lambda_id.eq(lambda_id))
+                    .order(lambda_versions::version.desc())
+                    .load::<models::LambdaVersion>(&*conn)?)
+            }
+
+            fn find_version(&self, lambda_id: i32, version: i32) -> StorageResult<Option<models::LambdaVersion>> {
+                use self::schema::lambda_versions;
+
+                let conn = self.0.get()?;
+
+                Ok(lambda_versions::table
+                    .filter(lambda_versions::lambda_id.eq(lambda_id))
+                    .filter(lambda_versions::version.eq(version))
+                    .first::<models::LambdaVersion>(&*conn)
+                    .optional()?)
+            }
+
+            fn activate(&self, lambda_id: i32, version: &models::LambdaVersion) -> StorageResult<models::Lambda> {
+                use self::schema::lambdas;
+
+                let conn = self.0.get()?;
+                let conn: &$conn = &*conn;
+
+                Ok(conn.write_transaction(|| {
+                    diesel::update(lambdas::table.find(lambda_id))
+                        .set((lambdas::code.eq(&version.code),
+                              lambdas::version.eq(version.version),
+                              lambdas::code_cache.eq(&version.code_cache),
+                              lambdas::code_cache_tag.eq(&version.code_cache_tag)))
+                        .execute(conn)?;
+
+                    lambdas::table.find(lambda_id).first::<models::Lambda>(conn)
+                })?)
+            }
+
+            fn aliases(&self, lambda_id: i32) -> StorageResult<Vec<models::LambdaAlias>> {
+                use self::schema::lambda_aliases;
+
+                let conn = self.0.get()?;
+
+                Ok(lambda_aliases::table
+                    .filter(lambda_aliases::lambda_id.eq(lambda_id))
+                    .order(lambda_aliases::name.asc())
+                    .load::<models::LambdaAlias>(&*conn)?)
+            }
+
+            fn find_alias(&self, lambda_id: i32, name: &str) -> StorageResult<Option<models::LambdaAlias>> {
+                use self::schema::lambda_aliases;
+
+                let conn = self.0.get()?;
+
+                Ok(lambda_aliases::table
+                    .filter(lambda_aliases::lambda_id.eq(lambda_id))
+                    .filter(lambda_aliases::name.eq(name))
+                    .first::<models::LambdaAlias>(&*conn)
+                    .optional()?)
+            }
+
+            fn set_alias(&self, alias: &models::NewLambdaAlias) -> StorageResult<models::LambdaAlias> {
+                use self::schema::lambda_aliases;
+
+                let conn = self.0.get()?;
+                let conn: &$conn = &*conn;
+
+                let find = || lambda_aliases::table
+                    .filter(lambda_aliases::lambda_id.eq(alias.lambda_id))
+                    .filter(lambda_aliases::name.eq(alias.name))
+                    .first::<models::LambdaAlias>(conn);
+
+                Ok(conn.write_transaction(|| {
+                    match find().optional()? {
+                        Some(existing) => {
+                            diesel::update(lambda_aliases::table.find(existing.id))
+                                .set(lambda_aliases::version.eq(alias.version))
+                                .execute(conn)?;
+                        },
+                        None => {
+                            diesel::insert_into(lambda_aliases::table).values(alias).execute(conn)?;
+                        },
+                    }
+                    find()
+                })?)
+            }
+
+            fn delete_alias(&self, id: i32) -> StorageResult<()> {
+                use self::schema::lambda_aliases;
+
+                let conn = self.0.get()?;
+
+                diesel::delete(lambda_aliases::table.find(id)).execute(&*conn)?;
+                Ok(())
+            }
+
+            fn traffic(&self, lambda_id: i32) -> StorageResult<Vec<models::LambdaTraffic>> {
+                use self::schema::lambda_traffic;
+
+                let conn = self.0.get()?;
+
+                Ok(lambda_traffic::table
+                    .filter(lambda_traffic::lambda_id.eq(lambda_id))
+                    .order(lambda_traffic::version.asc())
+                    .load::<models::LambdaTraffic>(&*conn)?)
+            }
+
+            fn set_traffic(&self, lambda_id: i32, sticky: Option<&str>, routes: &[models::NewLambdaTraffic]) -> StorageResult<models::Lambda> {
+                use self::schema::{lambdas, lambda_traffic};
+
+                let conn = self.0.get()?;
+                let conn: &$conn = &*conn;
+
+                Ok(conn.write_transaction(|| {
+                    diesel::delete(lambda_traffic::table.filter(lambda_traffic::lambda_id.eq(lambda_id)))
+                        .execute(conn)?;
+                    for route in routes.iter() {
+                        diesel::insert_into(lambda_traffic::table).values(route).execute(conn)?;
+                    }
+
+                    diesel::update(lambdas::table.find(lambda_id))
+                        .set(lambdas::sticky.eq(sticky))
+                        .execute(conn)?;
+
+                    lambdas::table.find(lambda_id).first::<models::Lambda>(conn)
+                })?)
+            }
+
+            fn set_limits(&self, lambda_id: i32, limits: &models::LambdaLimits) -> StorageResult<models::Lambda> {
+                use self::schema::lambdas;
+
+                let conn = self.0.get()?;
+
+                diesel::update(lambdas::table.find(lambda_id))
+                    .set(limits)
+                    .execute(&*conn)?;
+
+                Ok(lambdas::table.find(lambda_id).first::<models::Lambda>(&*conn)?)
+            }
+
+            fn host_limits(&self, hostname: &str) -> StorageResult<Option<models::HostLimits>> {
+                use self::schema::host_limits;
+
+                let conn = self.0.get()?;
+
+                Ok(host_limits::table
+                    .find(hostname)
+                    .first::<models::HostLimits>(&*conn)
+                    .optional()?)
+            }
+
+            fn set_host_limits(&self, limits: &models::HostLimits) -> StorageResult<models::HostLimits> {
+                use self::schema::host_limits;
+
+                let conn = self.0.get()?;
+                let conn: &$conn = &*conn;
+
+                let find = || host_limits::table
+                    .find(&limits.hostname)
+                    .first::<models::HostLimits>(conn);
+
+                Ok(conn.write_transaction(|| {
+                    match find().optional()? {
+                        Some(_) => {
+                            diesel::update(host_limits::table.find(&limits.hostname))
+                                .set(limits)
+                                .execute(conn)?;
+                        },
+                        None => {
+                            diesel::insert_into(host_limits::table).values(limits).execute(conn)?;
+                        },
+                    }
+                    find()
+                })?)
+            }
+
+            fn set_egress(&self, lambda_id: i32, policy: Option<&str>) -> StorageResult<models::Lambda> {
+                use self::schema::lambdas;
+
+                let conn = self.0.get()?;
+
+                diesel::update(lambdas::table.find(lambda_id))
+                    .set(lambdas::egress_policy.eq(policy))
+                    .execute(&*conn)?;
+
+                Ok(lambdas::table.find(lambda_id).first::<models::Lambda>(&*conn)?)
+            }
+
+            fn host_egress(&self, hostname: &str) -> StorageResult<Option<models::HostEgress>> {
+                use self::schema::host_egress;
+
+                let conn = self.0.get()?;
+
+                Ok(host_egress::table
+                    .find(hostname)
+                    .first::<models::HostEgress>(&*conn)
+                    .optional()?)
+            }
+
+            fn set_host_egress(&self, hostname: &str, policy: Option<&str>) -> StorageResult<Option<models::HostEgress>> {
+                use self::schema::host_egress;
+
+                let conn = self.0.get()?;
+                let conn: &$conn = &*conn;
+
+                Ok(conn.write_transaction(|| {
+                    diesel::delete(host_egress::table.find(hostname)).execute(conn)?;
+                    if let Some(policy) = policy {
+                        let egress = models::HostEgress { hostname: hostname.to_string(), policy: policy.to_string() };
+                        diesel::insert_into(host_egress::table).values(&egress).execute(conn)?;
+                    }
+                    host_egress::table.find(hostname).first::<models::HostEgress>(conn).optional()
+                })?)
+            }
+
+            fn notify_change(&self, hostname: &str) -> StorageResult<()> {
+                let conn = self.0.get()?;
+
+                Ok(NotifyChange::notify_change(&*conn, hostname)?)
+            }
+
+            fn api_keys(&self) -> StorageResult<Vec<models::ApiKey>> {
+                use self::schema::api_keys;
+
+                let conn = self.0.get()?;
+
+                Ok(api_keys::table
+                    .order(api_keys::id.asc())
+                    .load::<models::ApiKey>(&*conn)?)
+            }
+
+            fn find_api_key(&self, key_hash: &str) -> StorageResult<Option<models::ApiKey>> {
+                use self::schema::api_keys;
+
+                let conn = self.0.get()?;
+
+                Ok(api_keys::table
+                    .filter(api_keys::key_hash.eq(key_hash))
+                    .first::<models::ApiKey>(&*conn)
+                    .optional()?)
+            }
+
+            fn create_api_key(&self, key: &models::NewApiKey) -> StorageResult<models::ApiKey> {
+                use self::schema::api_keys;
+
+                let conn = self.0.get()?;
+
+                diesel::insert_into(api_keys::table).values(key).execute(&*conn)?;
+
+                Ok(api_keys::table
+                    .filter(api_keys::key_hash.eq(key.key_hash))
+                    .first::<models::ApiKey>(&*conn)?)
+            }
+
+            fn delete_api_key(&self, id: i32) -> StorageResult<()> {
+                use self::schema::api_keys;
+
+                let conn = self.0.get()?;
+
+                diesel::delete(api_keys::table.find(id)).execute(&*conn)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+diesel_storage!(PgStorage, PgConnection, postgres);
+diesel_storage!(SqliteStorage, SqliteConnection, sqlite);
+
+/// SQLite only enforces foreign keys (and with them `ON DELETE CASCADE`)
+/// when asked to, per connection.
+#[derive(Debug)]
+struct SqlitePragmas;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for SqlitePragmas {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.execute("PRAGMA foreign_keys = ON")
+            .and_then(|_| conn.execute("PRAGMA busy_timeout = 5000"))
+            .map(|_| ())
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Picks the backend from the `DATABASE_URL` scheme: `postgres://` (or
+/// `postgresql://`) for Postgres and `sqlite://<file>` for SQLite.
+pub fn connect(database_url: &str) -> Result<Arc<Storage>, String> {
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        let manager = ConnectionManager::<PgConnection>::new(database_url);
+        let pool = Pool::builder()
+            .build(manager)
+            .map_err(|e| format!("Failed to create pool: {}", e))?;
+        Ok(Arc::new(PgStorage(pool)))
+    } else if database_url.starts_with("sqlite:") {
+        let file = sqlite_path(database_url);
+        // each connection of the pool would open its own, empty database
+        if file.is_empty() || file == ":memory:" || file.starts_with("file::memory:") || file.contains("mode=memory") {
+            return Err(format!("Unsupported DATABASE_URL: {}, SQLite needs a database file", database_url));
+        }
+        let manager = ConnectionManager::<SqliteConnection>::new(file);
+        let pool = Pool::builder()
+            .connection_customizer(Box::new(SqlitePragmas))
+            .build(manager)
+            .map_err(|e| format!("Failed to create pool: {}", e))?;
+        Ok(Arc::new(SqliteStorage(pool)))
+    } else {
+        Err(format!("Unsupported DATABASE_URL: {}", database_url))
+    }
+}
+
+/// `sqlite://data.db` -> `data.db`, `sqlite:///var/data.db` -> `/var/data.db`
+pub fn sqlite_path(database_url: &str) -> &str {
+    let rest = database_url.trim_left_matches("sqlite:");
+    if rest.starts_with("//") { &rest[2..] } else { rest }
+}
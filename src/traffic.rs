@@ -0,0 +1,70 @@
+extern crate rand;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use models::{LambdaTraffic};
+use request::{Request};
+
+/// Checks that a sticky setting is either `header:<name>` or `cookie:<name>`.
+pub fn valid_sticky(sticky: &str) -> bool {
+    let mut parts = sticky.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("header"), Some(name)) | (Some("cookie"), Some(name)) => !name.is_empty(),
+        _ => false,
+    }
+}
+
+/// Picks the version that serves `request` from the weighted routes. When a
+/// sticky value is present the choice is a hash of it, so the same client
+/// keeps hitting the same version while the weights stay unchanged.
+pub fn pick(routes: &[LambdaTraffic], sticky: Option<&str>, request: &Request) -> Option<i32> {
+    let total: u64 = routes.iter().map(|r| weight(r)).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let bucket =
+        match sticky.and_then(|s| sticky_value(s, request)) {
+            Some(value) => {
+                let mut hasher = DefaultHasher::new();
+                value.hash(&mut hasher);
+                hasher.finish() % total
+            },
+            None => rand::random::<u64>() % total,
+        };
+
+    let mut upper = 0;
+    for route in routes.iter() {
+        upper += weight(route);
+        if bucket < upper {
+            return Some(route.version);
+        }
+    }
+    None
+}
+
+fn weight(route: &LambdaTraffic) -> u64 {
+    if route.weight > 0 { route.weight as u64 } else { 0 }
+}
+
+fn sticky_value(sticky: &str, request: &Request) -> Option<String> {
+    let mut parts = sticky.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("header"), Some(name)) => request.header(name),
+        (Some("cookie"), Some(name)) => {
+            request.header("cookie").and_then(|cookies| {
+                cookies.split(';')
+                    .filter_map(|pair| {
+                        let mut kv = pair.trim().splitn(2, '=');
+                        match (kv.next(), kv.next()) {
+                            (Some(k), Some(v)) if k == name => Some(v.to_string()),
+                            _ => None,
+                        }
+                    })
+                    .next()
+            })
+        },
+        _ => None,
+    }
+}